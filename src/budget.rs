@@ -0,0 +1,306 @@
+//! Functions for hitting a target size with two-pass encoding.
+
+use log::Level;
+use std::{fmt, path::Path, process::Command};
+
+use super::{
+    remote::{self, Analysis, Pass, Task},
+    Config, Host, MediaInfo, Result,
+};
+
+/// The part of a target size that's set aside for the container.
+const OVERHEAD: f64 = 0.01;
+/// How much the quantizer follows the complexity of frames, which is what
+/// `x264` does by default. Bits then grow with the complexity to the power
+/// of one minus this.
+const QCOMPRESS: f64 = 0.6;
+
+/// What the output of a two-pass encode should add up to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Budget {
+    /// A size in bytes, including the audio.
+    Size(u64),
+    /// An average video bitrate in bits per second.
+    BitRate(u64),
+}
+
+impl Budget {
+    /// Returns the average video bitrate in bits per second that meets the
+    /// budget for the input described by `info`.
+    pub fn video_bit_rate(self, info: &MediaInfo) -> Result<u64> {
+        match self {
+            Budget::Size(bytes) => {
+                let total = bytes as f64 * 8.0 * (1.0 - OVERHEAD);
+                let audio = info.audio_bit_rate() as f64 * info.duration;
+                let rate = (total - audio) / info.duration;
+                if !rate.is_finite() || rate < 1.0 {
+                    return Err(format!(
+                        "Target size of {} bytes is too small for the audio \
+                         alone",
+                        bytes
+                    )
+                    .into());
+                }
+                Ok(rate as u64)
+            }
+            Budget::BitRate(rate) => Ok(rate),
+        }
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Budget::Size(bytes) => write!(f, "{} bytes", bytes),
+            Budget::BitRate(rate) => write!(f, "{} kb/s", rate / 1000),
+        }
+    }
+}
+
+/// Divides the bits of an `average` video bitrate over chunks of the given
+/// lengths in seconds by their complexities, returning the bitrate of every
+/// chunk.
+///
+/// If the complexity of any chunk is unknown, they all get the average.
+pub fn allocate(average: u64, chunks: &[(f64, Option<f64>)]) -> Vec<u64> {
+    let complexities: Option<Vec<f64>> =
+        chunks.iter().map(|(_, complexity)| *complexity).collect();
+    let total: f64 = complexities.iter().flatten().sum();
+    match complexities {
+        Some(complexities) if total > 0.0 => {
+            let seconds: f64 = chunks.iter().map(|(length, _)| length).sum();
+            let bits = average as f64 * seconds;
+            chunks
+                .iter()
+                .zip(complexities)
+                .map(|((length, _), complexity)| {
+                    (bits * complexity / total / length.max(f64::EPSILON))
+                        as u64
+                })
+                .collect()
+        }
+        _ => vec![average; chunks.len()],
+    }
+}
+
+/// Returns the complexity of a chunk from the statistics of its first pass,
+/// if they're in the text format of `x264` or `x265`.
+///
+/// Every frame took some bits at some quantizer. Their product is how
+/// complex the frame is, which decides how many bits it gets in the second
+/// pass.
+pub fn complexity(stats: &str) -> Option<f64> {
+    let frames: Vec<f64> = stats
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let field = |key: &str| {
+                line.split_whitespace()
+                    .find_map(|field| field.strip_prefix(key))
+                    .and_then(|value| value.parse::<f64>().ok())
+            };
+            let bits = field("tex:")? + field("mv:")? + field("misc:")?;
+            Some(bits * qscale(field("q:")?))
+        })
+        .collect();
+    if frames.is_empty() {
+        return None;
+    }
+
+    Some(
+        frames
+            .iter()
+            .map(|complexity| complexity.powf(1.0 - QCOMPRESS))
+            .sum(),
+    )
+}
+
+/// Converts an H.264 quantizer into the scale it applies to coefficients.
+fn qscale(qp: f64) -> f64 {
+    0.85 * 2f64.powf((qp - 12.0) / 6.0)
+}
+
+/// Returns the `ffmpeg` arguments for a pass of a two-pass encode at the
+/// given bitrate, which replaces any rate control in `args`.
+pub fn pass_args(
+    args: &[String],
+    bit_rate: u64,
+    pass: u32,
+    passlog: &str,
+) -> Vec<String> {
+    let replaced = ["-crf", "-qp", "-b:v", "-pass", "-passlogfile"];
+    let mut kept = Vec::with_capacity(args.len() + 6);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if replaced.contains(&arg.as_str()) {
+            // Skip its value as well
+            args.next();
+        } else {
+            kept.push(arg.clone());
+        }
+    }
+    kept.extend([
+        "-b:v".to_string(),
+        bit_rate.to_string(),
+        "-pass".to_string(),
+        pass.to_string(),
+        "-passlogfile".to_string(),
+        passlog.to_string(),
+    ]);
+    kept
+}
+
+/// Returns the remote prefix of the first-pass statistics of a chunk for a
+/// rendition.
+pub fn passlog(dir: &str, chunk: &Path, rendition: &str) -> String {
+    format!(
+        "{}/pass_{}_{}",
+        dir,
+        rendition,
+        chunk.file_stem().expect("No normal file").to_string_lossy()
+    )
+}
+
+/// Builds the `ssh` command running the first pass over a chunk on a host,
+/// which only writes the statistics.
+pub fn first_pass_command(
+    host: &Host,
+    dir: &str,
+    chunk: &Path,
+    args: &[String],
+) -> Command {
+    let input = remote::remote_name(dir, chunk);
+    let mut remote_args = vec!["ffmpeg", "-y", "-i", &input];
+    remote_args.extend(args.iter().map(String::as_str));
+    remote_args.extend(["-f", "null", "/dev/null"]);
+    remote::ssh_command(host, remote_args)
+}
+
+/// Builds the `ssh` command printing the first-pass statistics of the video
+/// stream, which `ffmpeg` names after its index.
+pub fn stats_command(host: &Host, passlog: &str) -> Command {
+    remote::ssh_command(host, ["cat", &format!("{}-0.log", passlog)])
+}
+
+/// Plans the second pass of every chunk once all first passes have been
+/// `analysed`, dividing an `average` video bitrate over the chunks of each
+/// rendition.
+pub fn second_pass(
+    config: &Config,
+    analysed: &[Analysis],
+    average: u64,
+) -> Vec<Task> {
+    let mut tasks = Vec::with_capacity(analysed.len());
+    for (index, rendition) in config.renditions.iter().enumerate() {
+        let mut analyses: Vec<&Analysis> = analysed
+            .iter()
+            .filter(|analysis| analysis.task.rendition == index)
+            .collect();
+        analyses.sort_by(|a, b| a.task.chunk.cmp(&b.task.chunk));
+        let chunks: Vec<(f64, Option<f64>)> = analyses
+            .iter()
+            .map(|analysis| (analysis.task.span.duration, analysis.complexity))
+            .collect();
+
+        let label = config.label(rendition);
+        if chunks.iter().any(|(_, complexity)| complexity.is_none()) {
+            event!(
+                Level::Warn,
+                stage = "analyse",
+                rendition = rendition.name;
+                "Unable to read the first-pass statistics of every chunk{}, \
+                 so they all get the average bitrate",
+                label
+            );
+        }
+        let rates = allocate(average, &chunks);
+        let min = rates.iter().min().copied().unwrap_or_default();
+        let max = rates.iter().max().copied().unwrap_or_default();
+        event!(
+            Level::Info,
+            stage = "analyse",
+            rendition = rendition.name;
+            "Gave the chunks{} between {} and {} kb/s",
+            label,
+            min / 1000,
+            max / 1000
+        );
+        tasks.extend(analyses.into_iter().zip(rates).map(
+            |(analysis, bit_rate)| Task {
+                pass: Pass::Second {
+                    bit_rate,
+                    analysed_by: analysis.visit,
+                },
+                ..Task::new(
+                    analysis.task.chunk.clone(),
+                    analysis.task.span,
+                    index,
+                )
+            },
+        ));
+    }
+
+    tasks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_by_complexity() {
+        let chunks = [(10.0, Some(1.0)), (10.0, Some(3.0))];
+        assert_eq!(allocate(1000, &chunks), [500, 1500]);
+    }
+
+    #[test]
+    fn allocates_by_length() {
+        let chunks = [(10.0, Some(1.0)), (5.0, Some(1.0))];
+        assert_eq!(allocate(1000, &chunks), [750, 1500]);
+    }
+
+    #[test]
+    fn falls_back_to_average() {
+        assert_eq!(
+            allocate(1000, &[(10.0, Some(1.0)), (5.0, None)]),
+            [1000, 1000]
+        );
+        assert_eq!(allocate(1000, &[(10.0, Some(0.0))]), [1000]);
+    }
+
+    #[test]
+    fn weighs_frames_by_bits_and_quantizer() {
+        let stats = "#options: 1920x1080\n\
+            in:0 out:0 type:I dur:2 cpbdur:2 q:24.00 aq:20.00 tex:9000 \
+            mv:500 misc:500 imb:8160 pmb:0 smb:0 d:- ref:;\n\
+            in:1 out:1 type:P dur:2 cpbdur:2 q:18.00 aq:17.00 tex:4000 \
+            mv:200 misc:0 imb:0 pmb:8000 smb:160 d:- ref:0 ;\n";
+        let expected = (10_000.0 * qscale(24.0)).powf(1.0 - QCOMPRESS)
+            + (4200.0 * qscale(18.0)).powf(1.0 - QCOMPRESS);
+        assert!((complexity(stats).unwrap() - expected).abs() < 1e-9);
+        assert_eq!(complexity("#options: 1920x1080\n"), None);
+        assert_eq!(complexity("garbage"), None);
+    }
+
+    #[test]
+    fn replaces_rate_control() {
+        let args: Vec<String> = ["-c:v", "libx264", "-crf", "23", "-an"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            pass_args(&args, 2_000_000, 1, "dir/pass_main_chunk_000"),
+            [
+                "-c:v",
+                "libx264",
+                "-an",
+                "-b:v",
+                "2000000",
+                "-pass",
+                "1",
+                "-passlogfile",
+                "dir/pass_main_chunk_000"
+            ]
+        );
+    }
+}
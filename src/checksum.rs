@@ -0,0 +1,34 @@
+//! Functions for verifying that files arrive intact.
+
+use sha2::{Digest, Sha256};
+use std::{fs::File, io, path::Path, process::Command};
+
+use super::{remote, Host};
+
+/// Computes the SHA-256 hash of a local file as a hex string.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Builds the `ssh` command hashing a file on a host with `sha256sum`.
+pub fn remote_hash_command(host: &Host, path: &str) -> Command {
+    remote::ssh_command(host, ["sha256sum", path])
+}
+
+/// Computes the SHA-256 hash of a file on a host, returning `None` if it
+/// failed.
+pub fn remote_hash(host: &Host, path: &str) -> Option<String> {
+    let output = remote_hash_command(host, path).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // The output is the hash followed by the file name
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .map(ToString::to_string)
+}
@@ -0,0 +1,193 @@
+//! Functions for changing the hosts of a running job.
+
+use crossbeam::channel::Sender;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+};
+#[cfg(unix)]
+use {
+    crossbeam::channel,
+    log::debug,
+    std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::{UnixListener, UnixStream},
+        thread,
+        time::Duration,
+    },
+};
+
+use super::Host;
+
+/// The name of the control socket in the local temporary directory.
+pub const SOCKET: &str = "control.sock";
+/// How long a client has to send its request.
+#[cfg(unix)]
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A change to the hosts requested over the control socket.
+#[cfg_attr(not(unix), allow(dead_code))]
+#[derive(Debug)]
+pub enum Request {
+    /// Start encoding on another host.
+    Add(Host),
+    /// Stop giving chunks to every host with this name, so it leaves once
+    /// it's done with the ones it has.
+    Drain(String),
+}
+
+/// Where to answer a request, with the reason if it was rejected.
+pub type Reply = Sender<Result<(), String>>;
+
+/// Parses a request of the form `add <host>`, where the host is given like a
+/// line of a hosts file, or `drain <name>`.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn parse(line: &str) -> Result<Request, String> {
+    let line = line.trim();
+    let (command, argument) =
+        line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let argument = argument.trim();
+    match command {
+        "add" if !argument.is_empty() => {
+            Host::from_line(argument).map(Request::Add)
+        }
+        "drain" if !argument.is_empty() => {
+            Ok(Request::Drain(argument.to_string()))
+        }
+        _ => Err(format!("Expected add <host> or drain <name>, got {}", line)),
+    }
+}
+
+/// The thread listening on the control socket of a job.
+pub struct Listener {
+    /// Where the socket is.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    socket: PathBuf,
+    /// Tells the thread to stop.
+    done: Arc<AtomicBool>,
+}
+
+impl Listener {
+    /// Opens a control socket at `socket` and forwards the requests coming
+    /// in to `sender` from a thread of its own.
+    #[cfg(unix)]
+    pub fn start(
+        socket: &Path,
+        sender: Sender<(Request, Reply)>,
+    ) -> io::Result<Self> {
+        let listener = UnixListener::bind(socket)?;
+        let done = Arc::new(AtomicBool::new(false));
+        let d = Arc::clone(&done);
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || listen(listener, sender, d))?;
+
+        Ok(Self {
+            socket: socket.to_path_buf(),
+            done,
+        })
+    }
+
+    /// Fails, since there are only Unix domain sockets on Unix.
+    #[cfg(not(unix))]
+    pub fn start(
+        _socket: &Path,
+        _sender: Sender<(Request, Reply)>,
+    ) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Control sockets are only supported on Unix",
+        ))
+    }
+
+    /// Stops the thread listening for requests.
+    pub fn stop(self) {
+        self.done.store(true, Ordering::SeqCst);
+        // Accepting blocks, so connect once more to wake it up
+        #[cfg(unix)]
+        UnixStream::connect(&self.socket).ok();
+    }
+}
+
+/// Accepts connections on the control socket and forwards the requests
+/// coming in to `sender`, until `done` is set.
+///
+/// Every connection carries a single request on one line, which is answered
+/// with `ok` or the reason it was rejected once the job has handled it.
+/// Clients that don't send a full line in time are hung up on, so they can't
+/// keep the others waiting.
+#[cfg(unix)]
+fn listen(
+    listener: UnixListener,
+    sender: Sender<(Request, Reply)>,
+    done: Arc<AtomicBool>,
+) {
+    for stream in listener.incoming() {
+        if done.load(Ordering::SeqCst) {
+            break;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        stream.set_read_timeout(Some(READ_TIMEOUT)).ok();
+        let mut line = String::new();
+        if BufReader::new(&stream).read_line(&mut line).is_err() {
+            continue;
+        }
+        let reply = match parse(&line) {
+            Ok(request) => {
+                debug!("Control thread received {:?}", request);
+                let (reply, answer) = channel::bounded(1);
+                if sender.send((request, reply)).is_err() {
+                    // The job is done
+                    return;
+                }
+                match answer.recv() {
+                    Ok(Ok(())) => "ok".to_string(),
+                    Ok(Err(e)) => e,
+                    // The job ended before getting to it
+                    Err(_) => "The job is done".to_string(),
+                }
+            }
+            Err(e) => e,
+        };
+        // The connection is closed once the answer is out
+        writeln!(stream, "{}", reply).ok();
+    }
+    debug!("Control thread exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_add() {
+        match parse("add bigbox:4 user=me\n") {
+            Ok(Request::Add(host)) => {
+                assert_eq!(host.name, "bigbox");
+                assert_eq!(host.slots, 4);
+                assert_eq!(host.user.as_deref(), Some("me"));
+            }
+            other => panic!("Expected add, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_drain() {
+        match parse("  drain   laptop ") {
+            Ok(Request::Drain(name)) => assert_eq!(name, "laptop"),
+            other => panic!("Expected drain, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        for line in ["", "add", "drain ", "remove laptop", "add bigbox:0"] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Structured logging of the events happening during a job.
+//!
+//! Events are regular log records, so they show up like any other message in
+//! the terminal, but they additionally carry key-value pairs describing the
+//! stage, host, chunk and so on for loggers that know how to use them.
+
+/// Logs a message together with key-value pairs describing the event.
+///
+/// ```ignore
+/// event!(Level::Info, stage = "encode", host = host; "{} is done", host);
+/// ```
+///
+/// For a set of pairs only known at runtime, pass anything implementing
+/// `log::kv::Source` with `@source` instead.
+///
+/// ```ignore
+/// event!(@source Level::Info, &fields; "{} is done", host);
+/// ```
+macro_rules! event {
+    (@source $lvl:expr, $source:expr; $($arg:tt)+) => {
+        if $lvl <= log::max_level() {
+            log::logger().log(
+                &log::Record::builder()
+                    .args(format_args!($($arg)+))
+                    .level($lvl)
+                    .target(module_path!())
+                    .module_path_static(Some(module_path!()))
+                    .file_static(Some(file!()))
+                    .line(Some(line!()))
+                    .key_values($source)
+                    .build(),
+            );
+        }
+    };
+    ($lvl:expr, $($key:ident = $value:expr),+; $($arg:tt)+) => {
+        event!(
+            @source $lvl,
+            &&[$((
+                stringify!($key),
+                log::kv::ToValue::to_value(&$value),
+            )),+][..];
+            $($arg)+
+        )
+    };
+}
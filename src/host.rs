@@ -0,0 +1,234 @@
+//! The description of encoding hosts.
+
+use log::kv::{ToValue, Value};
+use std::{fmt, path::PathBuf, str::FromStr};
+
+/// How long a shared SSH connection stays open without being used. It's
+/// closed explicitly at the end of a job, this only matters if we can't.
+const CONTROL_PERSIST: u32 = 60;
+
+/// An encoding host.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Host {
+    /// The name used to connect to the host with SSH.
+    pub name: String,
+    /// The number of chunks the host encodes at the same time.
+    pub slots: usize,
+    /// How fast each of its encoders is compared to those of other hosts,
+    /// which only matters for picking the chunk length.
+    pub power: f64,
+    /// The user to log in as.
+    pub user: Option<String>,
+    /// The port SSH listens on.
+    pub port: Option<u16>,
+    /// The private key to authenticate with.
+    pub identity: Option<String>,
+    /// The hosts to jump through to reach this one, as for `ProxyJump`.
+    pub jump: Option<String>,
+    /// Further SSH options of the form `Key=Value`.
+    pub options: Vec<String>,
+    /// The socket over which all commands of a job share one connection,
+    /// which [`run`](crate::run) takes care of.
+    pub control_path: Option<PathBuf>,
+}
+
+impl Host {
+    /// Creates a host with a single encoder slot of power 1.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            slots: 1,
+            power: 1.0,
+            ..Self::default()
+        }
+    }
+
+    /// Parses hosts from lines of the form `name[:slots] [key=value ...]`.
+    ///
+    /// The keys are `user`, `port`, `identity`, `jump`, `power` and `option`,
+    /// which can be given several times.
+    pub fn from_lines(lines: &str) -> Result<Vec<Self>, String> {
+        super::parse_lines(lines, Self::from_line)
+    }
+
+    /// Parses a single line of a hosts file.
+    pub(crate) fn from_line(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        // Lines with only whitespace are skipped, so there's a first word
+        let mut host: Self = words.next().unwrap_or_default().parse()?;
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got {}", word))?;
+            match key {
+                "user" => host.user = Some(value.to_string()),
+                "port" => {
+                    host.port = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("Invalid port {}", value))?,
+                    )
+                }
+                "identity" => host.identity = Some(value.to_string()),
+                "power" => {
+                    host.power = value
+                        .parse()
+                        .ok()
+                        .filter(|&power: &f64| {
+                            power.is_finite() && power > 0.0
+                        })
+                        .ok_or_else(|| format!("Invalid power {}", value))?
+                }
+                "jump" => host.jump = Some(value.to_string()),
+                "option" => host.options.push(value.to_string()),
+                _ => return Err(format!("Unknown key {}", key)),
+            }
+        }
+
+        Ok(host)
+    }
+
+    /// Returns the options for `ssh` and `scp` to connect to the host.
+    ///
+    /// They're all given with `-o`, since that's the same for both, unlike
+    /// for example the flag for the port.
+    pub fn ssh_options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Some(user) = &self.user {
+            options.push(format!("User={}", user));
+        }
+        if let Some(port) = self.port {
+            options.push(format!("Port={}", port));
+        }
+        if let Some(identity) = &self.identity {
+            options.push(format!("IdentityFile={}", identity));
+        }
+        if let Some(jump) = &self.jump {
+            options.push(format!("ProxyJump={}", jump));
+        }
+        options.extend(self.options.iter().cloned());
+        if let Some(control_path) = &self.control_path {
+            options.push("ControlMaster=auto".to_string());
+            options.push(format!("ControlPath={}", control_path.display()));
+            options.push(format!("ControlPersist={}", CONTROL_PERSIST));
+        }
+
+        options
+            .into_iter()
+            .flat_map(|option| vec!["-o".to_string(), option])
+            .collect()
+    }
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    /// Parses a host of the form `name` or `name:slots`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, slots) = match s.rsplit_once(':') {
+            Some((name, slots)) => {
+                let slots =
+                    slots.parse().ok().filter(|&slots| slots > 0).ok_or_else(
+                        || format!("Invalid number of slots {}", s),
+                    )?;
+                (name, slots)
+            }
+            None => (s, 1),
+        };
+        if name.is_empty() {
+            return Err(format!("Missing host name in {}", s));
+        }
+
+        Ok(Self {
+            slots,
+            ..Self::new(name)
+        })
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl ToValue for Host {
+    fn to_value(&self) -> Value<'_> {
+        Value::from(self.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_name_and_slots() {
+        let host: Host = "bigbox:4".parse().unwrap();
+        assert_eq!(host.name, "bigbox");
+        assert_eq!(host.slots, 4);
+        assert_eq!("laptop".parse(), Ok(Host::new("laptop")));
+    }
+
+    #[test]
+    fn rejects_invalid_slots() {
+        for host in ["bigbox:0", "bigbox:x", "bigbox:", ":4"] {
+            assert!(host.parse::<Host>().is_err(), "{} was accepted", host);
+        }
+    }
+
+    #[test]
+    fn parses_ssh_options() {
+        let host = Host::from_line(
+            "bigbox:2 user=me port=2222 identity=~/.ssh/id jump=bastion \
+             option=Compression=yes option=BatchMode=yes",
+        )
+        .unwrap();
+        assert_eq!(host.slots, 2);
+        assert_eq!(host.user.as_deref(), Some("me"));
+        assert_eq!(host.port, Some(2222));
+        assert_eq!(host.identity.as_deref(), Some("~/.ssh/id"));
+        assert_eq!(host.jump.as_deref(), Some("bastion"));
+        assert_eq!(host.options, ["Compression=yes", "BatchMode=yes"]);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        for line in ["bigbox user", "bigbox port=x", "bigbox color=red"] {
+            assert!(Host::from_line(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn parses_power() {
+        assert_eq!(Host::new("laptop").power, 1.0);
+        let host = Host::from_line("laptop power=0.5").unwrap();
+        assert_eq!(host.power, 0.5);
+        for line in ["laptop power=0", "laptop power=-1", "laptop power=inf"] {
+            assert!(Host::from_line(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn passes_options_to_ssh() {
+        let host =
+            Host::from_line("bigbox user=me port=2222 option=A=b").unwrap();
+        assert_eq!(
+            host.ssh_options(),
+            ["-o", "User=me", "-o", "Port=2222", "-o", "A=b"]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_empty_lines() {
+        let hosts = Host::from_lines("# hosts\n\nbigbox:4\n  laptop  \n");
+        assert_eq!(
+            hosts.map(|hosts| hosts.into_iter().map(|h| h.name).collect()),
+            Ok(vec!["bigbox".to_string(), "laptop".to_string()])
+        );
+        assert_eq!(
+            Host::from_lines("bigbox\nlaptop port=x"),
+            Err("Line 2: Invalid port x".to_string())
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Functions for picking the length of chunks.
+
+use std::str::FromStr;
+
+use super::{Host, MediaInfo, Result};
+
+/// How many chunks an encoder should get when the chunk length is picked
+/// automatically. The more there are, the less time the others wait for the
+/// last one, but every chunk comes with a transfer and a start of `ffmpeg`.
+const CHUNKS_PER_ENCODER: f64 = 4.0;
+/// The shortest chunk length that is picked automatically in seconds, below
+/// which the overhead of every chunk outweighs the gains.
+const MIN_AUTO_LENGTH: f64 = 10.0;
+/// The longest chunk length that is picked automatically in seconds.
+const MAX_AUTO_LENGTH: f64 = 300.0;
+
+/// How to pick the length of chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkLength {
+    /// A fixed number of seconds.
+    Seconds(u64),
+    /// Picked from the duration of the input and the hosts.
+    Auto,
+    /// Whatever splits the input into this many chunks.
+    Count(u32),
+    /// Whatever makes chunks about this many bytes.
+    Size(u64),
+}
+
+impl ChunkLength {
+    /// Returns the chunk length in seconds for the input described by `info`
+    /// and encoded on `hosts`.
+    ///
+    /// Chunks can only start at keyframes, so any but a fixed length is at
+    /// least the distance between them.
+    pub fn seconds(self, info: &MediaInfo, hosts: &[Host]) -> Result<f64> {
+        let length = match self {
+            ChunkLength::Seconds(seconds) => return Ok(seconds as f64),
+            ChunkLength::Auto => auto(info.duration, hosts),
+            ChunkLength::Count(count) => info.duration / count as f64,
+            ChunkLength::Size(bytes) => {
+                let rate = info
+                    .video_bit_rate()
+                    .ok_or("Unable to find the bitrate of the input")?;
+                bytes as f64 * 8.0 / rate
+            }
+        };
+
+        Ok(length.max(info.video.keyframe_interval.unwrap_or_default()))
+    }
+
+    /// Describes what the length is picked for, for messages.
+    pub fn goal(self) -> String {
+        match self {
+            ChunkLength::Seconds(seconds) => format!("{} s", seconds),
+            ChunkLength::Auto => "the duration and the hosts".to_string(),
+            ChunkLength::Count(count) => format!("{} chunks", count),
+            ChunkLength::Size(bytes) => format!("chunks of {} bytes", bytes),
+        }
+    }
+}
+
+impl FromStr for ChunkLength {
+    type Err = String;
+
+    /// Parses a chunk length of the form `seconds` or `auto`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ChunkLength::Auto),
+            _ => s
+                .parse()
+                .ok()
+                .filter(|&seconds| seconds > 0)
+                .map(ChunkLength::Seconds)
+                .ok_or_else(|| format!("Invalid chunk length {}", s)),
+        }
+    }
+}
+
+/// Picks a chunk length in seconds for encoding `duration` seconds of video
+/// on `hosts`.
+///
+/// The last chunk of the slowest encoder keeps everyone else waiting, so the
+/// length is picked for it to take a fraction of the time the whole job
+/// needs, going by the power of the hosts. It's kept within sensible limits.
+fn auto(duration: f64, hosts: &[Host]) -> f64 {
+    let capacity: f64 = hosts
+        .iter()
+        .map(|host| host.slots as f64 * host.power)
+        .sum();
+    let slowest = hosts
+        .iter()
+        .map(|host| host.power)
+        .min_by(f64::total_cmp)
+        .unwrap_or(1.0);
+    // The hosts have at least the capacity of the slowest one
+    let length =
+        duration * slowest / (CHUNKS_PER_ENCODER * capacity.max(slowest));
+
+    length.clamp(MIN_AUTO_LENGTH, MAX_AUTO_LENGTH).ceil()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe::VideoStream;
+
+    fn info(
+        bit_rate: Option<u64>,
+        keyframe_interval: Option<f64>,
+    ) -> MediaInfo {
+        MediaInfo {
+            duration: 600.0,
+            format: "mov,mp4".to_string(),
+            size: None,
+            video: VideoStream {
+                codec: "h264".to_string(),
+                width: 1920,
+                height: 1080,
+                frame_rate: Some(25.0),
+                bit_rate,
+                keyframe_interval,
+            },
+            audio: Vec::new(),
+            other: Vec::new(),
+        }
+    }
+
+    fn hosts(lines: &[&str]) -> Vec<Host> {
+        lines.iter().map(|l| Host::from_line(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn auto_gives_every_encoder_a_few_chunks() {
+        // 4 encoders with 4 chunks each
+        let hosts = hosts(&["bigbox:2", "laptop", "desktop"]);
+        assert_eq!(auto(1600.0, &hosts), 100.0);
+        assert_eq!(auto(1601.0, &hosts), 101.0);
+    }
+
+    #[test]
+    fn auto_keeps_the_slowest_encoder_short() {
+        // The laptop takes twice as long, so its chunks are half as long as
+        // 4 chunks for each of the 2.5 encoders of full power
+        let hosts = hosts(&["bigbox:2", "laptop power=0.5"]);
+        assert_eq!(auto(2000.0, &hosts), 100.0);
+    }
+
+    #[test]
+    fn auto_stays_within_limits() {
+        let hosts = hosts(&["laptop"]);
+        assert_eq!(auto(20.0, &hosts), MIN_AUTO_LENGTH);
+        assert_eq!(auto(36_000.0, &hosts), MAX_AUTO_LENGTH);
+        assert_eq!(auto(600.0, &[]), 150.0);
+    }
+
+    #[test]
+    fn parses_seconds_and_auto() {
+        assert_eq!("auto".parse(), Ok(ChunkLength::Auto));
+        assert_eq!("30".parse(), Ok(ChunkLength::Seconds(30)));
+        for length in ["0", "-5", "1.5", "Auto"] {
+            assert!(length.parse::<ChunkLength>().is_err());
+        }
+    }
+
+    #[test]
+    fn splits_by_count_and_size() {
+        let hosts = hosts(&["laptop"]);
+        let info = info(Some(8_000_000), None);
+        let seconds = |length: ChunkLength| length.seconds(&info, &hosts);
+        assert_eq!(seconds(ChunkLength::Count(20)).unwrap(), 30.0);
+        assert_eq!(seconds(ChunkLength::Size(50_000_000)).unwrap(), 50.0);
+        let info = self::info(None, None);
+        assert!(ChunkLength::Size(50_000_000)
+            .seconds(&info, &hosts)
+            .is_err());
+    }
+
+    #[test]
+    fn keeps_chunks_as_long_as_the_keyframe_interval() {
+        let hosts = hosts(&["laptop"]);
+        let info = info(None, Some(40.0));
+        let seconds = |length: ChunkLength| length.seconds(&info, &hosts);
+        assert_eq!(seconds(ChunkLength::Count(20)).unwrap(), 40.0);
+        assert_eq!(seconds(ChunkLength::Seconds(10)).unwrap(), 10.0);
+    }
+}
@@ -22,28 +22,62 @@
 //! The prerequisites are one or more (you'll want more) computers—which we'll
 //! refer to as hosts—with `ffmpeg` installed and configured such that you can
 //! SSH into them directly. This means you'll have to `ssh-copy-id` your public
-//! key to them. I only tested it on Linux, but if you manage to set up
-//! `ffmpeg` and SSH, it might work on macOS or Windows directly or with little
-//! modification.
+//! key to them. The hosts also need `sha256sum`, which is used to verify that
+//! chunks arrive intact. Locally, you need `ffmpeg` and `ffprobe`, which is
+//! used to check the output. I only tested it on Linux, but if you manage to
+//! set up `ffmpeg` and SSH, it might work on macOS or Windows directly or
+//! with little modification.
 //!
 //! The usage is pretty straightforward:
 //! ```text
 //! USAGE:
-//!     shepherd [FLAGS] [OPTIONS] <IN> <OUT> --clients <hostnames> [FFMPEG OPTIONS]...
+//!     shepherd [FLAGS] [OPTIONS] <IN> <OUT> --clients <hostnames> [-- <FFMPEG OPTIONS>...]
+//!     shepherd [FLAGS] [OPTIONS] <SUBCOMMAND>
 //!
 //! FLAGS:
-//!     -h, --help       Prints help information
-//!     -k, --keep       Don't clean up temporary files
-//!     -V, --version    Prints version information
+//!     -n, --dry-run      Print the execution plan without touching any host
+//!     -h, --help         Prints help information
+//!     -k, --keep         Don't clean up temporary files
+//!         --no-verify    Don't check the encoded chunks and the output against the source
+//!     -V, --version      Prints version information
 //!
 //! OPTIONS:
-//!     -c, --clients <hostnames>    Comma-separated list of encoding hosts
-//!     -l, --length <seconds>       The length of video chunks in seconds
-//!     -t, --tmp <path>             The path to the local temporary directory
+//!         --chunk-size <size>                Split the video into chunks of about this size instead, in bytes or with a
+//!                                            suffix as in 200M
+//!         --chunks <count>                   Split the video into this many chunks instead
+//!     -c, --clients <hostnames>              Comma-separated list of encoding hosts, each optionally followed by the
+//!                                            number of chunks to encode on it at the same time as in bigbox:4
+//!         --crf-range <min-max>              The CRFs to search for the target quality [18-38]
+//!         --end <time>                       Where to stop encoding IN, given like the start
+//!     -H, --hosts <path>                     File with an encoding host per line, optionally followed by SSH options as
+//!                                            in: bigbox:4 user=me port=2222 identity=~/.ssh/id_big jump=bastion power=2
+//!                                            option=Compression=yes
+//!     -l, --length <seconds>                 The length of video chunks in seconds, or auto to pick one from the duration
+//!                                            of IN and the hosts
+//!         --log-file <path>                  Write log messages to a file instead of stderr
+//!         --log-format <format>              The format of log messages [default: term]  [possible values: term, json]
+//!     -m, --metrics <metrics>                Comma-separated list of quality metrics to compute for every chunk [possible
+//!                                            values: ssim, psnr, vmaf]
+//!         --probes <count>                   The maximum number of probe encodes per chunk for the target quality [4]
+//!     -R, --renditions <path>                File with a rendition per line to encode instead of OUT, given by name, scale
+//!                                            (- for none), output and optionally ffmpeg options as in: 720p -2:720
+//!                                            out_720p.mp4 -c:v libx264 -crf 24
+//!         --segment <seconds>                The segment length in seconds if OUT is an HLS (.m3u8) or DASH (.mpd) package
+//!                                            [6]
+//!         --start <time>                     Where to start encoding IN, in seconds or as in 1:30 or 1:02:03.5
+//!         --target-bitrate <rate>            Encode in two passes so the video of OUT has this average bitrate, in bits
+//!                                            per second or with a suffix as in 2500k
+//!         --target-quality <metric=score>    Pick the CRF for every chunk so it reaches this score, e.g. vmaf=93
+//!         --target-size <size>               Encode in two passes so OUT ends up about this size, in bytes or with a
+//!                                            suffix as in 700M
+//!     -t, --tmp <path>                       The path to the local temporary directory
+//!     -Z, --zones <path>                     File with a time range per line to encode with other ffmpeg options, given by
+//!                                            start, end (- for the end of IN) and the options that replace or add to the
+//!                                            others as in: 1:25:00 - -crf 32
 //!
 //! ARGS:
 //!     <IN>                   The original video file
-//!     <OUT>                  The output video file
+//!     <OUT>                  The output video file, or the playlist of an HLS (.m3u8) or DASH (.mpd) package
 //!     <FFMPEG OPTIONS>...    Options/flags for ffmpeg encoding of chunks. The
 //!                            chunks are video only, so don't pass in anything
 //!                            concerning audio. Input/output file names are added
@@ -54,7 +88,13 @@
 //!                            -crf 26 -preset veryslow -profile:v high -level 4.2
 //!                            -pix_fmt yuv420p
 //!                            This is also the default that is used if no options
-//!                            are provided.
+//!                            are provided. {index}, {start}, {duration} and {host}
+//!                            are replaced with those of every chunk.
+//!
+//! SUBCOMMANDS:
+//!     help      Prints this message or the help of the given subcommand(s)
+//!     sample    Encodes evenly spaced samples of IN to tell how fast every host is and how long and big the whole job
+//!               would be, without writing OUT
 //! ```
 //!
 //! So if we have three machines c1, c2 and c3, we could do
@@ -69,31 +109,203 @@
 //! ```console
 //! $ shepherd -c c1,c2 input.mkv output.mp4 -- -c:v libx264 -crf 40
 //! ```
+//! These options can differ between chunks with the placeholders `{index}`
+//! (the number of the chunk, from 0), `{start}` and `{duration}` (where it
+//! is in the input, in seconds) and `{host}` (the host encoding it), e.g.
+//! `-x265-params stats=stats_{index}.log`. Other braces are left alone.
+//!
+//! To publish for a web player, name the output after a playlist. With
+//! `output.m3u8` you get an HLS package of fMP4 segments with the audio as a
+//! separate rendition, and with `output.mpd` a DASH package. The encoded
+//! chunks are given a keyframe at every segment boundary of the output, so
+//! the segments are `--segment` seconds long (6 by default) no matter where
+//! the chunks start and end.
+//!
+//! To serve several qualities at once, list them in a file given with
+//! `--renditions` instead of OUT, one per line with a name, the size to scale
+//! to (`-` to keep the input's), the output and optionally its own `ffmpeg`
+//! options, which default to those after `--`:
+//! ```text
+//! # name scale output [ffmpeg options...]
+//! 1080p -2:1080 out_1080p.m3u8 -c:v libx264 -crf 22
+//! 720p -2:720 out_720p.m3u8 -c:v libx264 -crf 24
+//! 480p -2:480 out_480p.m3u8
+//! ```
+//! The input is split only once, and every chunk is transferred to a host
+//! only once no matter how many renditions it encodes from it.
+//!
+//! Some encoders don't manage to keep a big machine busy on their own. For
+//! those, you can append the number of chunks a host should encode at the
+//! same time to its name, so `-c bigbox:4,laptop` runs four encoders on
+//! `bigbox` and one on `laptop`.
+//!
+//! Hosts that need more than a name to connect to can go into a file given
+//! with `--hosts`, one per line with their SSH options:
+//! ```text
+//! # name[:slots] [user=...] [port=...] [identity=...] [jump=...] [option=...]
+//! bigbox:4 user=encoder port=2222 identity=~/.ssh/id_bigbox
+//! hidden jump=bastion option=Compression=yes
+//! ```
+//! Either way, all commands of a job share a single SSH connection per host,
+//! which saves the handshake for every chunk.
+//!
+//! Rather than guessing a chunk length, you can pass `--length auto`. It's
+//! picked so that every encoder gets around four chunks, which keeps the
+//! time everybody waits for the last one short without paying for a transfer
+//! and a start of `ffmpeg` too often. It stays between 10 and 300 s and
+//! isn't shorter than the distance between keyframes. If some hosts are
+//! faster than others, tell it with `power` in the hosts file, e.g. `power=3`
+//! for a host whose encoders are three times as fast as those of a host
+//! without, or `power=0.5` for one that's half as fast. The chunks are sized
+//! for the slowest one.
+//!
+//! You can also ask for a number of chunks with `--chunks`, e.g. four times
+//! the number of encoders, or for chunks of about the same size with
+//! `--chunk-size`, e.g. `200M`, which makes their transfer time predictable
+//! on slow links. The size is converted into a length using the bitrate of
+//! the input, so the chunks of a video with a varying bitrate vary as well.
+//!
+//! To encode only part of the input, say where with `--start` and `--end`,
+//! in seconds or as in `1:30`. Only that part is split, encoded and combined
+//! with the same part of the audio. The chunks are copied from the input,
+//! so the start is moved back to the keyframe before it, and the audio
+//! starts there as well to stay in sync.
+//!
+//! Parts of the video that don't need the same settings as the rest, like
+//! the credits, can go into a file given with `--zones`, one per line with
+//! a start, an end (`-` for the end of the input) and `ffmpeg` options:
+//! ```text
+//! # start end ffmpeg options...
+//! 0 1:30 -crf 30
+//! 1:25:00 - -crf 32 -tune stillimage
+//! ```
+//! The options replace the values of the same options for the chunks in the
+//! zone and are added otherwise. The video is split at the zone boundaries
+//! (or rather the first keyframe after them), so every chunk is in at most
+//! one zone, and the parts in between are divided evenly into chunks of at
+//! most the chunk length. Since `--target-quality` and `--target-size` pick
+//! the rate control themselves, a `-crf` in a zone doesn't change anything
+//! with them.
+//!
+//! The hosts can also change while the job is running. At the start, shepherd
+//! logs the path of a control socket in its temporary directory, which takes
+//! one request per connection:
+//! ```console
+//! $ echo "add workstation:2 user=me" | nc -U ~/shepherd_tmp_<job>/control.sock
+//! $ echo "drain laptop" | nc -U ~/shepherd_tmp_<job>/control.sock
+//! ```
+//! A host that's added is given as in a hosts file and starts taking chunks
+//! right away, unless it's already part of the job. A drained host doesn't
+//! take any more chunks and leaves once it's done with the ones it has,
+//! removing its temporary files, so it can be added again later. If that
+//! leaves no hosts, the job waits for one to be added, while it fails when
+//! they're all gone without being drained. Control sockets are only available
+//! on Unix.
+//!
+//! Before committing to a long job, you can add `--dry-run` to see what would
+//! happen. It probes the input with `ffprobe` (so you'll need that locally),
+//! shows the expected chunk boundaries and prints every `ffmpeg`, `ssh` and
+//! `scp` command that would be run for each stage and host, without touching
+//! any of them.
+//!
+//! To find out what new settings are like before spending a night on them,
+//! put `sample` in front of the other arguments:
+//! ```console
+//! $ shepherd sample -c c1,c2 input.mkv output.mp4 -- -c:v libx265 -crf 28
+//! ```
+//! This encodes five evenly spaced samples of 10 s (`--samples` and
+//! `--sample-length` change that) on the hosts and logs how fast every host
+//! is, how long the whole job would take and how big the output would be.
+//! OUT isn't written, but with `--keep-samples` the encoded samples are kept
+//! next to it as `output_sample_01.mp4` and so on for a closer look.
+//!
+//! To help with picking encoding settings, `--metrics ssim,psnr,vmaf` has
+//! every host compare each chunk it encoded to the source chunk it still has
+//! lying around. The scores of every chunk and their means over the whole
+//! file, weighted by the length of the chunks, are logged at the end. VMAF is
+//! skipped on hosts where `ffmpeg` wasn't built with `libvmaf`. Scaled
+//! renditions are scaled back to the size of the source for the comparison,
+//! but keep in mind that the metrics compare frames one to one, so they only
+//! make sense if your options don't change the frame rate.
+//!
+//! Since different scenes need very different CRF values to look equally
+//! good, you can also let the hosts pick it per chunk. With
+//! `--target-quality vmaf=93`, every host encodes each chunk a few times
+//! (`--probes`, 4 by default) in a binary search over `--crf-range` (18-38 by
+//! default) and keeps the highest CRF that still reaches the score. The
+//! chosen values end up in the quality report. This replaces any `-crf` in
+//! your options, so only use it with encoders that understand it.
+//!
+//! When the output has to fit somewhere, `--target-size 700M` or
+//! `--target-bitrate 2500k` encodes every chunk in two passes instead. Each
+//! host runs a first pass over its chunks, and once all of them are done,
+//! the video bits left after the audio are divided by how complex every
+//! chunk turned out to be, so busy scenes get more than quiet ones. This
+//! reads the statistics of `libx264` and `libx265`; with other encoders
+//! every chunk gets the average. If a chunk's second pass ends up on
+//! another host, that host repeats the first pass, since the statistics stay
+//! where they were written. This replaces any `-crf`, `-qp` or `-b:v` in
+//! your options, so only use it with encoders that support `-pass`.
+//!
+//! If another program needs to follow the progress, `--log-format json`
+//! switches the log to one JSON object per line, with the `timestamp`,
+//! `level` and `message` of every event plus its `stage`, `host`, `chunk`,
+//! `duration` and `error` where they apply. Use `--log-file` to write it to a
+//! file instead of stderr.
 //!
 //! ## How it works
 //!
-//! 1. Creates a temporary directory in your home directory.
-//! 2. Extracts the audio and encodes it. This is not parallelized, but the
-//!    time this takes is negligible compared to the video anyway.
+//! 1. Creates a temporary directory in your home directory, named after a
+//!    job ID that's unique to this run, and puts a lock file with the process
+//!    ID in it. This way several jobs can run at the same time, even against
+//!    the same hosts. The directories of jobs that crashed, whose lock file
+//!    names a process that's gone, are removed, and so are theirs on the
+//!    hosts of this job.
+//! 2. Probes the input with `ffprobe` and warns about anything that won't
+//!    turn out as you might expect, like keyframes that are further apart
+//!    than the chunk length or streams that are dropped. Then extracts the
+//!    audio and encodes it, with a higher bitrate for surround sound. This is
+//!    not parallelized, but the time this takes is negligible compared to the
+//!    video anyway.
 //! 3. Splits the video into chunks. This can take relatively long, since
 //!    you're basically writing the full file to disk again. It would be nice
 //!    if we could read chunks of the file and directly transfer them to the
 //!    hosts, but that might be tricky with `ffmpeg`.
-//! 4. Spawns a manager and an encoder thread for every host. The manager
-//!    creates a temporary directory in the home directory of the remote and
-//!    makes sure that the encoder always has something to encode. It will
-//!    transfer a chunk, give it to the encoder to work on and meanwhile
-//!    transfer another chunk, so the encoder can start directly with that once
-//!    it's done, without wasting any time. But it will keep at most one chunk
-//!    in reserve, to prevent the case where a slow machine takes too many
-//!    chunks and is the only one still encoding while the faster ones are
-//!    already done.
-//! 5. When an encoder is done and there are no more chunks to work on, it will
-//!    quit and the manager transfers the encoded chunks back before
-//!    terminating itself.
+//! 4. Spawns a manager, an encoder (or one per slot if there are several)
+//!    and a returner thread for every host. The manager creates a temporary
+//!    directory for the job in the home directory of the remote and makes
+//!    sure that the encoders always have something to encode.
+//!    It will transfer a chunk, verify its checksum on the host (transferring
+//!    it again if it arrived corrupted), give it to the encoder to work on and
+//!    meanwhile transfer another chunk, so the encoder can start directly
+//!    with that once it's done, without wasting any time. But it will keep
+//!    at most one chunk in reserve, to prevent the case where a slow machine
+//!    takes too many chunks and is the only one still encoding while the
+//!    faster ones are already done.
+//! 5. As soon as the encoder is done with a chunk, the returner transfers it
+//!    back while the encoder continues with the next one, again checking it
+//!    against a checksum computed on the host. It then decodes the encoded
+//!    chunk and checks that it's as long as the source chunk, with as many
+//!    frames as it has, converted to the frame rate of the encoded chunk if
+//!    the options change it. An encoder that crashed halfway can leave a
+//!    truncated file behind, so a chunk that doesn't match is put back into
+//!    the queue to be encoded again, up to three times. If it comes out wrong
+//!    the same way twice, the job fails right away, since the options are to
+//!    blame. When there are no more chunks to work on, the threads terminate.
+//!    In the meantime, the progress is logged with an estimate of the time it
+//!    will take to encode the rest.
 //! 6. Once all encoded chunks have arrived, they're concatenated and the audio
-//!    stream added.
-//! 7. All remote and the local temporary directory are removed.
+//!    stream added, or packaged for HLS or DASH, once for every rendition.
+//! 7. The output is probed with `ffprobe` and checked against the source.
+//!    Every encoded chunk needs to match its source chunk again, the
+//!    output needs all frames of the encoded chunks and the duration of the
+//!    input, and its audio may not have drifted away from the video. If
+//!    anything is off, the job fails and keeps the local temporary directory
+//!    for a closer look. `--no-verify` skips this and the check of every
+//!    encoded chunk.
+//! 8. The job's remote and local temporary directories are removed, without
+//!    touching those of other jobs. With `--keep` they stay around and only
+//!    the lock file goes away.
 //!
 //! Thanks to the work stealing method of distribution, having some hosts that
 //! are significantly slower than others does not delay the overall operation.
@@ -208,101 +420,374 @@
 //! ## Limitations
 //!
 //! While you can use your own `ffmpeg` options to control how the video is
-//! encoded, there is currently no such option for the audio, which is 192 kb/s
-//! AAC by default.
+//! encoded, there is currently no such option for the audio. It's always AAC,
+//! at 192 kb/s for mono and stereo and at 384 kb/s for surround sound.
 
-use crossbeam::channel;
-use dirs;
-use log::{error, info};
+use crossbeam::channel::{self, Receiver, RecvTimeoutError};
+use log::Level;
 use std::{
+    env,
     error::Error,
-    fs,
+    fs::{self, OpenOptions},
+    io::Write,
     path::{Path, PathBuf},
-    process::Command,
+    process::{self, Command, Stdio},
     string::ToString,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     sync::Arc,
-    thread,
-    time::Duration,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+#[macro_use]
+mod events;
+mod budget;
+mod checksum;
+mod control;
+mod host;
+mod length;
 mod local;
+mod logging;
+mod package;
+mod plan;
+mod probe;
+mod quality;
+mod range;
 mod remote;
+mod rendition;
+mod sample;
+mod shell;
+mod template;
+mod verify;
+mod zone;
+
+pub use budget::Budget;
+pub use host::Host;
+pub use length::ChunkLength;
+pub use logging::JsonLogger;
+use package::Package;
+pub use probe::{AudioStream, MediaInfo, VideoStream};
+pub use quality::{Metric, Target};
+pub use range::parse_time;
+use range::Range;
+use remote::{Encoded, Pass, Queue, Task};
+pub use rendition::Rendition;
+pub use sample::Sample;
+use template::Span;
+pub use zone::Zone;
 
-/// The name of the temporary directory in the home directory to collect
-/// intermediate files.
+/// The prefix of the temporary directory in the home directory to collect
+/// intermediate files, which is followed by the job ID.
 const TMP_DIR: &str = "shepherd_tmp";
+/// The name of the lock file marking a local temporary directory as in use.
+const LOCK: &str = "shepherd.lock";
 /// The name of the encoded audio track.
 const AUDIO: &str = "audio.aac";
+/// How often to check on the host threads, for control requests and for
+/// chunks put back into the queue.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
 /// The length of chunks to split the video into.
-const DEFAULT_LENGTH: &str = "60";
+const DEFAULT_LENGTH: u64 = 60;
+/// The `ffmpeg` arguments for chunk encoding if none are given.
+pub const DEFAULT_ARGS: &[&str] = &[
+    "-c:v",
+    "libx264",
+    "-crf",
+    "26",
+    "-preset",
+    "veryslow",
+    "-profile:v",
+    "high",
+    "-level",
+    "4.2",
+    "-pix_fmt",
+    "yuv420p",
+];
+
+/// A thread managing a host, see [`remote::host_thread`].
+struct HostThread {
+    /// The name of the host.
+    name: String,
+    /// Tells the thread to stop taking chunks.
+    draining: Arc<AtomicBool>,
+    /// The thread, which returns the chunks it encoded.
+    handle: JoinHandle<Vec<Encoded>>,
+}
 
 /// The generic result type for this crate.
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+/// The settings of a job.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The path to the input file.
+    pub input: PathBuf,
+    /// The path to the output file.
+    pub output: PathBuf,
+    /// Arguments to `ffmpeg` for chunk encoding.
+    pub args: Vec<String>,
+    /// The encoding hosts.
+    pub hosts: Vec<Host>,
+    /// How to pick the length of video chunks.
+    pub length: ChunkLength,
+    /// Where to start encoding the input in seconds, its start if `None`.
+    pub start: Option<f64>,
+    /// Where to stop encoding the input in seconds, its end if `None`.
+    pub end: Option<f64>,
+    /// The path to the local temporary directory, the home directory if
+    /// `None`.
+    pub tmp_dir: Option<PathBuf>,
+    /// Whether to keep temporary files on hosts (no cleanup).
+    pub keep: bool,
+    /// The quality metrics to compute for every encoded chunk.
+    pub metrics: Vec<Metric>,
+    /// The quality to reach by picking the CRF for every chunk.
+    pub target: Option<Target>,
+    /// What the output should add up to with a two-pass encode.
+    pub budget: Option<Budget>,
+    /// The segment length in seconds if the output is an HLS (`.m3u8`) or
+    /// DASH (`.mpd`) package.
+    pub segment: u64,
+    /// The versions of the video to produce from the same chunks. Without
+    /// any, there's one with `args` and `output`.
+    pub renditions: Vec<Rendition>,
+    /// Whether to check every encoded chunk and the output against the
+    /// source.
+    pub verify: bool,
+    /// How to sample the input to preview the settings, instead of encoding
+    /// all of it.
+    pub sample: Option<Sample>,
+    /// The time ranges of the input with their own settings, in order.
+    pub zones: Vec<Zone>,
+    /// The unique ID of the job, which names its temporary directories. It's
+    /// picked anew every time the job runs.
+    pub(crate) job: String,
+    /// The IDs of crashed jobs whose temporary directories are removed from
+    /// the hosts.
+    pub(crate) crashed: Vec<String>,
+}
+
+impl Config {
+    /// Creates a configuration with the default settings.
+    pub fn new(
+        input: impl Into<PathBuf>,
+        output: impl Into<PathBuf>,
+        hosts: Vec<Host>,
+    ) -> Self {
+        Self {
+            input: input.into(),
+            output: output.into(),
+            args: DEFAULT_ARGS.iter().map(ToString::to_string).collect(),
+            hosts,
+            length: ChunkLength::Seconds(DEFAULT_LENGTH),
+            start: None,
+            end: None,
+            tmp_dir: None,
+            keep: false,
+            metrics: Vec::new(),
+            target: None,
+            budget: None,
+            segment: package::DEFAULT_SEGMENT,
+            renditions: Vec::new(),
+            verify: true,
+            sample: None,
+            zones: Vec::new(),
+            job: String::new(),
+            crashed: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns how to refer to a rendition in messages, which is not at all
+    /// if it's the only one.
+    fn label(&self, rendition: &Rendition) -> String {
+        if self.renditions.len() > 1 {
+            format!(" ({})", rendition.name)
+        } else {
+            String::new()
+        }
+    }
+
+    /// Finds the range of the input to encode, narrowing `info` down to it,
+    /// or `None` if it's all of it.
+    fn range(&self, info: &mut MediaInfo) -> Result<Option<Range>> {
+        Range::find(&self.input, info, self.start, self.end)
+    }
+
+    /// Returns the chunk length in seconds for the input described by
+    /// `info`.
+    fn chunk_length(&self, info: &MediaInfo) -> Result<f64> {
+        self.length.seconds(info, &self.hosts)
+    }
+
+    /// Returns the rendition with the arguments it encodes a chunk with on a
+    /// host, which have the options of the chunk's zone and the placeholders
+    /// filled in. HLS or DASH output gets a keyframe at every segment
+    /// boundary.
+    fn chunk_rendition(
+        &self,
+        rendition: &Rendition,
+        span: Span,
+        host: &str,
+    ) -> Rendition {
+        let args = match zone::find(&self.zones, span) {
+            Some(zone) => zone::apply(&rendition.args, &zone.args),
+            None => rendition.args.clone(),
+        };
+        let mut args = template::expand(&args, span, host);
+        if Package::from_output(&rendition.output).is_some() {
+            let position = span.start - span.offset;
+            args.extend(Package::keyframe_args(self.segment, position));
+        }
+
+        Rendition {
+            args,
+            ..rendition.clone()
+        }
+    }
+}
+
+/// Generates an ID that's unique among the jobs running at the same time.
+///
+/// It's made of the current time, the process ID and a count of the jobs in
+/// this process, so neither concurrent jobs on one machine nor jobs started
+/// by different machines at different times collide.
+fn job_id() -> String {
+    static JOBS: AtomicUsize = AtomicUsize::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let count = JOBS.fetch_add(1, Ordering::SeqCst);
+    format!("{:x}-{:x}-{:x}", millis, process::id(), count)
+}
+
+/// Removes the local temporary directories next to `tmp_dir` of jobs that
+/// crashed, returning their IDs.
+///
+/// A job crashed if its lock file names a process that's gone. Directories
+/// without one were kept on purpose, so they stay.
+fn remove_crashed(tmp_dir: &Path) -> Vec<String> {
+    let prefix = format!("{}_", TMP_DIR);
+    let entries = match tmp_dir.parent().map(fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return Vec::new(),
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let job = name.to_str()?.strip_prefix(&prefix)?.to_string();
+            let pid = fs::read_to_string(entry.path().join(LOCK)).ok()?;
+            if is_running(pid.trim()) {
+                return None;
+            }
+            fs::remove_dir_all(entry.path()).ok()?;
+            event!(
+                Level::Info,
+                stage = "cleanup";
+                "Removed {} of a crashed job",
+                entry.path().display()
+            );
+            Some(job)
+        })
+        .collect()
+}
+
+/// Returns whether the process with the ID is running, which it's assumed to
+/// be if we can't tell.
+fn is_running(pid: &str) -> bool {
+    if pid.parse::<u32>().is_err() {
+        return true;
+    }
+    Command::new("kill")
+        .args(["-0", pid])
+        .stderr(Stdio::null())
+        .status()
+        .map_or(true, |status| status.success())
+}
+
 /// Starts the whole operation and cleans up afterwards.
 ///
 /// # Arguments
-/// * `input` - The path to the input file.
-/// * `output` - The path to the output file.
-/// * `args` - Arguments to `ffmpeg` for chunk encoding.
-/// * `hosts` - Comma-separated list of hosts.
-/// * `seconds` - The video chunk length.
-/// * `tmp_dir` - The path to the local temporary directory.
-/// * `keep` - Whether to keep temporary files on hosts (no cleanup).
-pub fn run(
-    input: impl AsRef<Path>,
-    output: impl AsRef<Path>,
-    args: &[&str],
-    hosts: Vec<&str>,
-    seconds: Option<&str>,
-    tmp_dir: Option<&str>,
-    keep: bool,
-) -> Result<()> {
-    // Convert the length
-    let seconds = seconds.unwrap_or(DEFAULT_LENGTH).parse::<u64>()?;
+/// * `config` - The settings of the job.
+/// * `running` - Shared flag the caller sets to `false` to abort. Remote
+///   encoders finish their current chunk and quit gracefully.
+///
+/// No signal handlers are installed, it's up to the caller to arrange for
+/// `running` to be cleared when the user wants to abort.
+pub fn run(config: &Config, running: Arc<AtomicBool>) -> Result<()> {
+    let mut config = prepare(config)?;
     // Convert the tmp_dir
-    let mut tmp_dir = tmp_dir
-        .map(PathBuf::from)
-        .or_else(dirs::home_dir)
-        .ok_or("Home directory not found")?;
+    let tmp_dir = local_tmp_dir(config.tmp_dir.as_deref(), &config.job)?;
+    config.crashed = remove_crashed(&tmp_dir);
+    let config = &config;
 
-    // Set up a shared boolean to check whether the user has aborted
-    let running = Arc::new(AtomicBool::new(true));
-    let r = Arc::clone(&running);
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-        info!(
-            "Abort signal received. Waiting for remote encoders to finish the \
-             current chunk and quit gracefully."
-        );
-    })
-    .expect("Error setting Ctrl-C handler");
-
-    tmp_dir.push(TMP_DIR);
-    // Remove local temporary directory in case it's still around
-    fs::remove_dir_all(&tmp_dir).ok();
-    // Create our local temporary directory
+    // Create our local temporary directory. This fails if it already exists,
+    // so we never use the files of another job.
     fs::create_dir(&tmp_dir)?;
+    // Mark it as in use for as long as we're running
+    let lock = tmp_dir.join(LOCK);
+    let mut lock_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)?;
+    writeln!(lock_file, "{}", process::id())?;
+
+    // Open the control socket, so hosts can be added or drained at any
+    // point. The job can do without it, so failing isn't fatal.
+    let socket = tmp_dir.join(control::SOCKET);
+    let (control_sender, control_receiver) = channel::unbounded();
+    let listener = match control::Listener::start(&socket, control_sender) {
+        Ok(listener) => {
+            event!(
+                Level::Info,
+                stage = "control";
+                "Add or drain hosts by writing add <host> or drain <name> to \
+                 {}",
+                socket.display()
+            );
+            Some(listener)
+        }
+        Err(e) => {
+            // Nobody is going to send requests, since the sender is gone
+            event!(
+                Level::Warn,
+                stage = "control",
+                error = e.to_string();
+                "Failed opening control socket, hosts can't be changed: {}",
+                e
+            );
+            None
+        }
+    };
 
-    // Start the operation
+    // Start the operation. Hosts can be added while it's running, so it
+    // keeps track of all the hosts we have to clean up.
+    let mut hosts = config.hosts.clone();
     let result = run_local(
-        input.as_ref(),
-        output.as_ref(),
-        args,
+        config,
         &tmp_dir,
-        &hosts,
-        seconds,
+        &mut hosts,
+        &control_receiver,
         Arc::clone(&running),
     );
+    // Stop listening for requests
+    if let Some(listener) = listener {
+        listener.stop();
+    }
 
-    if !keep {
-        info!("Cleaning up");
+    // If the output came out wrong, the local files are needed to find out
+    // why
+    let mismatch = matches!(&result, Err(e) if e.is::<verify::Mismatch>());
+    if !config.keep {
+        event!(Level::Info, stage = "cleanup"; "Cleaning up");
         // Remove remote temporary directories
-        for &host in &hosts {
+        let remote_dir = remote::tmp_dir(&config.job);
+        for host in &hosts {
             // Clean up temporary directory on host
-            let output = Command::new("ssh")
-                .args(&[&host, "rm", "-r", remote::TMP_DIR])
+            let output = remote::cleanup_command(host, &remote_dir)
                 .output()
                 .expect("Failed executing ssh command");
             // These checks for `running` are necessary, because Ctrl + C also
@@ -312,38 +797,263 @@ pub fn run(
             // Unfortunately this also means we have to litter the `running`
             // variable almost everyhwere.
             if !output.status.success() && running.load(Ordering::SeqCst) {
-                error!(
+                event!(
+                    Level::Error,
+                    stage = "cleanup",
+                    host = host,
+                    error = "Failed removing remote temporary directory";
                     "Failed removing remote temporary directory on {}",
                     host
                 );
             }
         }
+    }
+    if config.keep || mismatch {
+        // Leave the files, but show they're no longer in use
+        fs::remove_file(&lock).ok();
+        if mismatch {
+            event!(
+                Level::Warn,
+                stage = "cleanup";
+                "Keeping {} to look into the mismatch",
+                tmp_dir.display()
+            );
+        }
+    } else {
         // Remove local temporary directory
         fs::remove_dir_all(&tmp_dir).ok();
     }
+    // Close the shared connections. If one was never opened, there's nothing
+    // to do, so the result doesn't matter.
+    for host in &hosts {
+        remote::close_command(host).output().ok();
+    }
 
     result
 }
 
+/// Describes what [`run`] would do with the same configuration, without
+/// touching any host.
+///
+/// The input is probed with `ffprobe` to compute the expected chunk
+/// boundaries, and the returned plan lists every command that would be
+/// executed locally and on each host.
+pub fn dry_run(config: &Config) -> Result<String> {
+    let config = &prepare(config)?;
+    let tmp_dir = local_tmp_dir(config.tmp_dir.as_deref(), &config.job)?;
+
+    plan::describe(config, &tmp_dir)
+}
+
+/// Tells every host thread to leave once it's done with its chunks and
+/// waits for them.
+///
+/// Like any drained host, they clean up after themselves unless we `keep`
+/// the temporary files, so they're removed from `hosts` then.
+fn stop(host_threads: Vec<HostThread>, hosts: &mut Vec<Host>, keep: bool) {
+    for thread in &host_threads {
+        thread.draining.store(true, Ordering::SeqCst);
+    }
+    for HostThread { name, handle, .. } in host_threads {
+        handle.join().ok();
+        if !keep {
+            hosts.retain(|host| host.name != name);
+        }
+    }
+}
+
+/// Logs how many of the `total` chunks are done and how long the remaining
+/// ones will take, going by how long the others took.
+///
+/// The speed is given in frames per second if we know the number of `frames`
+/// to encode.
+fn report_progress(
+    total: usize,
+    remaining: usize,
+    frames: Option<f64>,
+    elapsed: Duration,
+) {
+    let done = total - remaining;
+    let fraction = done as f64 / total as f64;
+    let left = elapsed.mul_f64(remaining as f64 / done as f64);
+    let speed = frames
+        .map(|frames| {
+            format!(" at {:.1} fps", frames * fraction / elapsed.as_secs_f64())
+        })
+        .unwrap_or_default();
+    let estimate = if remaining > 0 {
+        format!(", about {:.0?} left", left)
+    } else {
+        String::new()
+    };
+    event!(
+        Level::Info,
+        stage = "encode",
+        done = done,
+        total = total,
+        left = left.as_secs_f64();
+        "Encoded {} of {} chunks ({:.0}%){}{}",
+        done,
+        total,
+        fraction * 100.0,
+        speed,
+        estimate
+    );
+}
+
+/// Returns a copy of the configuration with what the job needs on top of the
+/// given settings.
+///
+/// The commands of the job share one SSH connection per host, unless a host
+/// has its own control path. Without renditions, there's one for the output,
+/// and renditions without arguments get the job's. Fails for segments of
+/// zero seconds, which would put a keyframe at every frame.
+///
+/// The job gets a new ID, so running it again doesn't collide with the last
+/// run.
+fn prepare(config: &Config) -> Result<Config> {
+    if config.segment == 0 {
+        return Err("The segment length needs to be at least a second".into());
+    }
+    let mut config = config.clone();
+    config.job = job_id();
+    for host in &mut config.hosts {
+        share_connection(host, &config.job);
+    }
+    if config.renditions.is_empty() {
+        config.renditions.push(Rendition::new(
+            rendition::DEFAULT_NAME,
+            config.output.clone(),
+        ));
+    }
+    for rendition in &mut config.renditions {
+        if rendition.args.is_empty() {
+            rendition.args = config.args.clone();
+        }
+    }
+
+    Ok(config)
+}
+
+/// Makes the commands of a job share one SSH connection to a host, unless it
+/// has its own control path.
+///
+/// The sockets go to the system's temporary directory, since their paths
+/// must not be longer than about 100 bytes.
+fn share_connection(host: &mut Host, job: &str) {
+    host.control_path.get_or_insert_with(|| {
+        env::temp_dir().join(format!("shepherd_{}_%C", job))
+    });
+}
+
+/// Returns the path of the local temporary directory of a job.
+fn local_tmp_dir(tmp_dir: Option<&Path>, job: &str) -> Result<PathBuf> {
+    let mut tmp_dir = tmp_dir
+        .map(PathBuf::from)
+        .or_else(dirs::home_dir)
+        .ok_or("Home directory not found")?;
+    tmp_dir.push(format!("{}_{}", TMP_DIR, job));
+
+    Ok(tmp_dir)
+}
+
+/// Parses the lines of a hosts, renditions or zones file with `from_line`.
+///
+/// Empty lines and those starting with `#` are skipped. Errors tell the
+/// number of the line they're about.
+fn parse_lines<T>(
+    lines: &str,
+    mut from_line: impl FnMut(&str) -> std::result::Result<T, String>,
+) -> std::result::Result<Vec<T>, String> {
+    lines
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            from_line(line).map_err(|e| format!("Line {}: {}", number, e))
+        })
+        .collect()
+}
+
+/// Isolates the output extension, since encoded chunks have the same.
+///
+/// For HLS and DASH packages, they're MP4 files to be segmented.
+fn output_extension(output: &Path) -> Result<String> {
+    if Package::from_output(output).is_some() {
+        return Ok("mp4".to_string());
+    }
+
+    Ok(local::ascii_extension(output)?.to_string())
+}
+
 /// Does the actual work.
 ///
 /// This is separate so it can fail and return early, since cleanup is then
-/// handled in its caller function.
+/// handled in its caller function. Hosts added over the control socket are
+/// appended to `hosts`, and drained ones that cleaned up after themselves
+/// are removed from it.
 fn run_local(
-    input: &Path,
-    output: &Path,
-    args: &[&str],
+    config: &Config,
     tmp_dir: &Path,
-    hosts: &[&str],
-    seconds: u64,
+    hosts: &mut Vec<Host>,
+    control: &Receiver<(control::Request, control::Reply)>,
     running: Arc<AtomicBool>,
 ) -> Result<()> {
+    let input = config.input.as_path();
+    // Find out what we're working with before doing anything to it
+    let mut info = MediaInfo::probe(input)?;
+    event!(Level::Info, stage = "probe"; "Input: {}", info);
+    // From here on, only the range counts
+    let range = config.range(&mut info)?;
+    if let Some(range) = range {
+        event!(Level::Info, stage = "probe"; "Encoding {} of the input", range);
+        if let Some(start) = config.start.filter(|&start| start > range.start)
+        {
+            event!(
+                Level::Info,
+                stage = "probe";
+                "Moved the start back from {:.2} s to the keyframe at {:.2} s",
+                start,
+                range.start
+            );
+        }
+    }
+    // Samples are the chunks when sampling
+    let seconds = match config.sample {
+        Some(sample) => sample.length,
+        None => config.chunk_length(&info)?,
+    };
+    if config.sample.is_none()
+        && !matches!(config.length, ChunkLength::Seconds(_))
+    {
+        event!(
+            Level::Info,
+            stage = "probe";
+            "Picked a chunk length of {:.2} s for {}",
+            seconds,
+            config.length.goal()
+        );
+    }
+    for warning in info.check(seconds)? {
+        event!(Level::Warn, stage = "probe"; "{}", warning);
+    }
+
     // Build path to audio file
     let mut audio = tmp_dir.to_path_buf();
     audio.push(AUDIO);
-    // Start the extraction
-    info!("Extracting audio");
-    local::extract_audio(input, &audio, &running)?;
+    // Samples aren't combined, so they don't need any
+    if config.sample.is_none() {
+        // Start the extraction
+        event!(Level::Info, stage = "audio"; "Extracting audio");
+        local::extract_audio(
+            input,
+            &audio,
+            info.audio_bit_rate(),
+            range,
+            &running,
+        )?;
+    }
 
     // We check whether the user has aborted before every time-intensive task.
     // It's a better experience, but a bit ugly code-wise.
@@ -356,17 +1066,50 @@ fn run_local(
     let mut chunk_dir = tmp_dir.to_path_buf();
     chunk_dir.push("chunks");
     fs::create_dir(&chunk_dir)?;
-    // Split the video
-    info!("Splitting video into chunks");
-    local::split_video(
-        input,
-        &chunk_dir,
-        Duration::from_secs(seconds),
-        &running,
-    )?;
+    let offset = range.map_or(0.0, |range| range.start);
+    let mut samples = Vec::new();
+    let mut spans = Vec::new();
+    if let Some(sample) = config.sample {
+        // Take the samples from the range if there is one
+        event!(
+            Level::Info,
+            stage = "sample";
+            "Extracting {} samples of {} s",
+            sample.count,
+            sample.length
+        );
+        let starts = sample.starts(offset, info.duration);
+        samples = sample.extract(input, &chunk_dir, &starts, &running)?;
+        spans = samples
+            .iter()
+            .zip(starts)
+            .enumerate()
+            .map(|(index, ((_, duration), start))| Span {
+                index,
+                start,
+                duration: *duration,
+                offset,
+            })
+            .collect();
+    } else {
+        // Split the video
+        event!(Level::Info, stage = "split"; "Splitting video into chunks");
+        // Chunks start and end with the zones
+        let cuts = Some(&config.zones)
+            .filter(|zones| !zones.is_empty())
+            .map(|zones| zone::cuts(zones, seconds, offset, info.duration));
+        local::split_video(
+            input,
+            &chunk_dir,
+            Duration::from_secs_f64(seconds),
+            cuts.as_deref(),
+            range,
+            &running,
+        )?;
+    }
     // Get the list of created chunks
     let mut chunks = fs::read_dir(&chunk_dir)?
-        .map(|res| res.and_then(|readdir| Ok(readdir.path())))
+        .map(|res| res.map(|readdir| readdir.path()))
         .collect::<std::io::Result<Vec<PathBuf>>>()?;
     // Sort them so they're in order. That's not strictly necessary, but nicer
     // for the user to watch since it allows seeing the progress at a glance.
@@ -376,70 +1119,381 @@ fn run_local(
         // Abort early
         return Ok(());
     }
+    // Find where the chunks are for the placeholders in the arguments
+    if config.sample.is_none() {
+        spans = Span::probe(&chunks, offset)?;
+    }
+    for (number, zone) in config.zones.iter().enumerate() {
+        let count = spans.iter().filter(|&&span| zone.contains(span)).count();
+        if count > 0 {
+            event!(
+                Level::Info,
+                stage = "split";
+                "Zone {} from {} applies {} to {} chunks",
+                number + 1,
+                zone,
+                zone.args.join(" "),
+                count
+            );
+        } else {
+            event!(
+                Level::Warn,
+                stage = "split";
+                "Zone {} from {} doesn't have most of any chunk, so it's not \
+                 used",
+                number + 1,
+                zone
+            );
+        }
+    }
 
-    // Initialize the global channel for chunks
-    let (sender, receiver) = channel::unbounded();
-    // Send all chunks into it
-    for chunk in chunks {
-        sender.send(chunk)?;
+    // With a budget, every chunk gets a first pass before the one that
+    // encodes it
+    let average = config
+        .budget
+        .map(|budget| budget.video_bit_rate(&info))
+        .transpose()?;
+    if let (Some(budget), Some(average)) = (config.budget, average) {
+        event!(
+            Level::Info,
+            stage = "analyse";
+            "Aiming for {} with {} kb/s of video on average",
+            budget,
+            average / 1000
+        );
     }
-    // Drop the sender so the channel gets disconnected
-    drop(sender);
+    let pass = average.map_or(Pass::Single, Pass::First);
 
-    // Since we want to share the ffmpeg arguments between the threads, we need
-    // to first set up an owned version of them
-    let args: Vec<String> = args.iter().map(ToString::to_string).collect();
-    // and then create our Arc
-    let args = Arc::new(args);
+    // Initialize the queue shared by all hosts with every chunk, once for
+    // every rendition
+    let tasks: Vec<Task> = chunks
+        .iter()
+        .zip(spans)
+        .flat_map(|(chunk, span)| {
+            let pass = &pass;
+            (0..config.renditions.len()).map(move |rendition| Task {
+                pass: pass.clone(),
+                ..Task::new(chunk.clone(), span, rendition)
+            })
+        })
+        .collect();
+    let first_passes = tasks.len();
+    let queue = Queue::new(tasks);
+    if average.is_some() {
+        // The second passes are added once the first ones are done
+        queue.expect(first_passes);
+    }
+
+    // Since we want to share the configuration between the threads, we
+    // create an Arc of it
+    let shared_config = Arc::new(config.clone());
 
-    // Create directory for encoded chunks
+    // Create directory for encoded chunks, with one for every rendition
     let mut encoded_dir = tmp_dir.to_path_buf();
     encoded_dir.push("encoded");
     fs::create_dir(&encoded_dir)?;
-    // Isolate output extension, since we want encoded chunks to have the same
-    let out_ext = output
-        .extension()
-        .ok_or("Unable to find extension")?
-        .to_str()
-        .ok_or("Unable to convert OsString extension")?
-        .to_string();
+    for rendition in &config.renditions {
+        fs::create_dir(encoded_dir.join(&rendition.name))?;
+        // Encoded chunks get the output extension, so make sure there is one
+        output_extension(&rendition.output)?;
+    }
     // Spawn threads for hosts
-    info!("Starting remote encoding");
-    let mut host_threads = Vec::with_capacity(hosts.len());
-    for &host in hosts {
-        // Clone the queue receiver for the thread
-        let thread_receiver = receiver.clone();
+    event!(Level::Info, stage = "encode"; "Starting remote encoding");
+    let spawn = |host: Host| -> Result<HostThread> {
+        // Clone the queue for the thread
+        let q = queue.clone();
         // Create copy of running indicator for the thread
         let r = Arc::clone(&running);
+        // And one to tell it to leave
+        let draining = Arc::new(AtomicBool::new(false));
+        let d = Arc::clone(&draining);
         // And lots of other copies because it's easy and the extra allocations
         // are not a problem for this kind of application
-        let host = host.to_string();
+        let name = host.name.clone();
         let enc = encoded_dir.clone();
-        let ext = out_ext.clone();
-        let a = Arc::clone(&args);
+        let c = Arc::clone(&shared_config);
         // Start it
-        let handle =
-            thread::Builder::new().name(host.clone()).spawn(|| {
-                remote::host_thread(host, thread_receiver, enc, ext, a, r);
-            })?;
-        host_threads.push(handle);
+        let handle = thread::Builder::new()
+            .name(name.clone())
+            .spawn(|| remote::host_thread(host, q, enc, c, d, r))?;
+        Ok(HostThread {
+            name,
+            draining,
+            handle,
+        })
+    };
+    let mut host_threads = Vec::with_capacity(config.hosts.len());
+    for host in &config.hosts {
+        match spawn(host.clone()) {
+            Ok(thread) => host_threads.push(thread),
+            Err(e) => {
+                stop(host_threads, hosts, config.keep);
+                return Err(e);
+            }
+        }
     }
 
-    // Wait for all hosts to finish
-    for handle in host_threads {
-        if handle.join().is_err() {
-            return Err("A host thread panicked".into());
+    // Wait for all hosts to finish, adding and draining hosts in the meantime
+    // as requested over the control socket
+    let mut encoded = Vec::new();
+    let mut analysed = Vec::new();
+    let mut waiting = false;
+    let mut control_open = true;
+    // Only hosts that were drained are expected to be replaced
+    let mut drained = false;
+    // Keep track of the progress, which every rendition adds to
+    let total = queue.remaining();
+    let frames = match config.sample {
+        Some(_) => info.video.frame_rate.map(|rate| {
+            rate * samples.iter().map(|(_, length)| length).sum::<f64>()
+        }),
+        None => info.frames(),
+    }
+    .map(|frames| {
+        let passes = if average.is_some() { 2.0 } else { 1.0 };
+        frames * config.renditions.len() as f64 * passes
+    });
+    let started = Instant::now();
+    let mut reported = total;
+    // If anything goes wrong, the hosts still need to be stopped
+    let mut failure = None;
+    loop {
+        match control.recv_timeout(POLL_INTERVAL) {
+            Ok((control::Request::Add(mut host), reply)) => {
+                // The remote directory is named after the job, so a host
+                // can only take part once at a time
+                let active = host_threads
+                    .iter()
+                    .find(|thread| thread.name == host.name);
+                let answer = match active {
+                    Some(thread) if thread.draining.load(Ordering::SeqCst) => {
+                        Err(format!(
+                            "{} is still draining, add it again once it left",
+                            host.name
+                        ))
+                    }
+                    Some(_) => {
+                        Err(format!("{} is already encoding", host.name))
+                    }
+                    None => {
+                        share_connection(&mut host, &config.job);
+                        event!(
+                            Level::Info,
+                            stage = "control",
+                            host = host;
+                            "Adding {}",
+                            host
+                        );
+                        hosts.retain(|other| other.name != host.name);
+                        hosts.push(host.clone());
+                        spawn(host)
+                            .map(|thread| host_threads.push(thread))
+                            .map_err(|e| e.to_string())
+                    }
+                };
+                if let Err(e) = &answer {
+                    event!(Level::Warn, stage = "control"; "{}", e);
+                }
+                reply.send(answer).ok();
+            }
+            Ok((control::Request::Drain(name), reply)) => {
+                let matching: Vec<&HostThread> = host_threads
+                    .iter()
+                    .filter(|thread| thread.name == name)
+                    .collect();
+                let answer = if matching.is_empty() {
+                    Err(format!(
+                        "Can't drain {}, since it's not encoding",
+                        name
+                    ))
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = &answer {
+                    event!(Level::Warn, stage = "control", host = name; "{}", e);
+                }
+                for thread in matching {
+                    thread.draining.store(true, Ordering::SeqCst);
+                    drained = true;
+                }
+                reply.send(answer).ok();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // Without the control socket nothing can change, so just wait
+            Err(RecvTimeoutError::Disconnected) => {
+                control_open = false;
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+
+        // Once every chunk has been analysed, the second passes can start
+        if let Some(average) = average {
+            let complete = analysed.len() == first_passes;
+            analysed.extend(queue.analyses());
+            if !complete && analysed.len() == first_passes {
+                for task in budget::second_pass(config, &analysed, average) {
+                    queue.add(task);
+                }
+            }
+        }
+
+        let remaining = queue.remaining();
+        if remaining < reported {
+            report_progress(total, remaining, frames, started.elapsed());
+            reported = remaining;
+        }
+
+        // A chunk that can't be encoded fails the whole job
+        if let Some(reason) = queue.failure() {
+            failure = Some(reason.into());
+            break;
+        }
+
+        // Collect the chunks of the hosts that are done
+        let (finished, active): (Vec<_>, Vec<_>) = host_threads
+            .into_iter()
+            .partition(|thread| thread.handle.is_finished());
+        host_threads = active;
+        for HostThread {
+            name,
+            draining,
+            handle,
+        } in finished
+        {
+            match handle.join() {
+                Ok(chunks) => encoded.extend(chunks),
+                Err(_) => failure = Some("A host thread panicked".into()),
+            }
+            // A drained host already removed its temporary directory
+            if draining.load(Ordering::SeqCst) && !config.keep {
+                hosts.retain(|host| host.name != name);
+            }
+        }
+        if failure.is_some() {
+            break;
+        }
+
+        if host_threads.is_empty() {
+            if queue.remaining() == 0 || !running.load(Ordering::SeqCst) {
+                break;
+            }
+            // Everybody left before the work was done, so we need new hosts.
+            // Unless some were drained to make room for others, none are
+            // coming.
+            if !control_open || !drained {
+                failure = Some(
+                    format!(
+                        "No hosts left with {} chunks to go",
+                        queue.remaining()
+                    )
+                    .into(),
+                );
+                break;
+            }
+            if control.is_empty() && !waiting {
+                event!(
+                    Level::Warn,
+                    stage = "control";
+                    "All hosts left with {} chunks to go, waiting for one to \
+                     be added",
+                    queue.remaining()
+                );
+                waiting = true;
+            }
+        } else {
+            waiting = false;
         }
     }
 
+    if let Some(e) = failure {
+        // Don't leave the others encoding in directories that are about to
+        // be removed
+        stop(host_threads, hosts, config.keep);
+        return Err(e);
+    }
     if !running.load(Ordering::SeqCst) {
         // We aborted early
         return Ok(());
     }
 
-    // Combine encoded chunks and audio
-    info!("Combining encoded chunks into final video");
-    local::combine(&encoded_dir, &audio, output, &running)?;
+    if !config.metrics.is_empty() || config.target.is_some() {
+        encoded.sort_by(|a, b| a.chunk.cmp(&b.chunk));
+        for rendition in &config.renditions {
+            let chunks: Vec<Encoded> = encoded
+                .iter()
+                .filter(|chunk| chunk.rendition == rendition.name)
+                .cloned()
+                .collect();
+            quality::report(&chunks, &config.label(rendition));
+        }
+    }
+
+    // Samples are only there to tell what encoding everything would be like
+    if let Some(sample) = config.sample {
+        return sample.report(
+            config,
+            &info,
+            hosts,
+            &samples,
+            &encoded,
+            &encoded_dir,
+        );
+    }
+
+    // Combine encoded chunks and audio for every rendition
+    for rendition in &config.renditions {
+        event!(
+            Level::Info,
+            stage = "combine",
+            rendition = rendition.name;
+            "Combining encoded chunks into {}",
+            rendition.output.display()
+        );
+        local::combine(
+            &encoded_dir.join(&rendition.name),
+            &audio,
+            &rendition.output,
+            config.segment,
+            range,
+            &running,
+        )?;
+    }
+
+    if config.verify && running.load(Ordering::SeqCst) {
+        event!(
+            Level::Info,
+            stage = "verify";
+            "Verifying output against the source"
+        );
+        let source = verify::Source::probe(input, &chunks, range, &running)?;
+        // Check every rendition before failing, so all problems get logged
+        let mut result = Ok(());
+        for rendition in &config.renditions {
+            let out_ext = output_extension(&rendition.output)?;
+            let rendition_dir = encoded_dir.join(&rendition.name);
+            let encoded: Vec<PathBuf> = chunks
+                .iter()
+                .map(|chunk| {
+                    rendition_dir.join(remote::encoded_file_name(
+                        chunk,
+                        &rendition.name,
+                        &out_ext,
+                    ))
+                })
+                .collect();
+            let checked = source.check(
+                &encoded,
+                &rendition.output,
+                &rendition.name,
+                &config.label(rendition),
+                &running,
+            );
+            if result.is_ok() {
+                result = checked;
+            }
+        }
+        result?;
+    }
 
     Ok(())
 }
@@ -9,23 +9,20 @@ use std::{
     time::Duration,
 };
 
-use super::Result;
+use super::{package::Package, range::Range, Result};
 
-/// Uses `ffmpeg` to locally extract and encode the audio.
+/// Uses `ffmpeg` to locally extract and encode the audio with the given
+/// bitrate in bits per second, only of the `range` if there is one.
 pub fn extract_audio(
     input: &Path,
     output: &Path,
+    bitrate: u64,
+    range: Option<Range>,
     running: &Arc<AtomicBool>,
 ) -> Result<()> {
-    // Convert input and output to &str
-    let input = input.to_str().ok_or("Input invalid Unicode")?;
-    let output = output.to_str().ok_or("Output invalid Unicode")?;
     // Do the extraction
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-y", "-i", input, "-vn", "-c:a", "aac", "-b:a", "192k", output,
-        ])
-        .output()?;
+    let output =
+        extract_audio_command(input, output, bitrate, range)?.output()?;
     if !output.status.success() && running.load(Ordering::SeqCst) {
         return Err("Failed extracting audio".into());
     }
@@ -33,40 +30,44 @@ pub fn extract_audio(
     Ok(())
 }
 
-/// Uses `ffmpeg` to locally split the video into chunks.
+/// Builds the `ffmpeg` command for extracting and encoding the audio.
+pub fn extract_audio_command(
+    input: &Path,
+    output: &Path,
+    bitrate: u64,
+    range: Option<Range>,
+) -> Result<Command> {
+    // Paths are passed as they are, so they don't need to be valid Unicode
+    let mut command = Command::new("ffmpeg");
+    command.arg("-y");
+    add_input(&mut command, input, range);
+    command
+        .args([
+            "-vn",
+            "-c:a",
+            "aac",
+            "-b:a",
+            &format!("{}k", bitrate / 1000),
+        ])
+        .arg(output);
+    Ok(command)
+}
+
+/// Uses `ffmpeg` to locally split the video into chunks, only of the `range`
+/// if there is one. The chunks are split at the `cuts` if given, counted
+/// from the start of the range, and every `segment_length` otherwise.
 pub fn split_video(
     input: &Path,
     output_dir: &Path,
     segment_length: Duration,
+    cuts: Option<&[f64]>,
+    range: Option<Range>,
     running: &Arc<AtomicBool>,
 ) -> Result<()> {
-    // Isolate file extension, since we want the chunks to have the same
-    let extension = input
-        .extension()
-        .ok_or("Unable to find extension")?
-        .to_str()
-        .ok_or("Unable to convert OsString extension")?;
-    // Convert input and output to &str
-    let input = input.to_str().ok_or("Input invalid Unicode")?;
-    let mut output_dir = output_dir.to_path_buf();
-    output_dir.push(format!("chunk_%03d.{}", extension));
-    let output = output_dir.to_str().ok_or("Output invalid Unicode")?;
     // Do the chunking
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-y",
-            "-i",
-            input,
-            "-an",
-            "-c",
-            "copy",
-            "-f",
-            "segment",
-            "-segment_time",
-            &segment_length.as_secs().to_string(),
-            output,
-        ])
-        .output()?;
+    let output =
+        split_video_command(input, output_dir, segment_length, cuts, range)?
+            .output()?;
     if !output.status.success() && running.load(Ordering::SeqCst) {
         return Err("Failed splitting video".into());
     }
@@ -74,18 +75,127 @@ pub fn split_video(
     Ok(())
 }
 
+/// Builds the `ffmpeg` command for splitting the video into chunks.
+pub fn split_video_command(
+    input: &Path,
+    output_dir: &Path,
+    segment_length: Duration,
+    cuts: Option<&[f64]>,
+    range: Option<Range>,
+) -> Result<Command> {
+    let extension = chunk_extension(input)?;
+    let mut output = output_dir.to_path_buf();
+    output.push(format!("chunk_%03d.{}", extension));
+
+    let mut command = Command::new("ffmpeg");
+    command.arg("-y");
+    add_input(&mut command, input, range);
+    command.args(["-an", "-c", "copy", "-f", "segment"]);
+    match cuts {
+        Some(cuts) if !cuts.is_empty() => {
+            let cuts: Vec<String> =
+                cuts.iter().map(ToString::to_string).collect();
+            command.args(["-segment_times", &cuts.join(",")]);
+        }
+        _ => {
+            command.args([
+                "-segment_time",
+                &segment_length.as_secs_f64().to_string(),
+            ]);
+        }
+    }
+    command.arg(output);
+    Ok(command)
+}
+
+/// Uses `ffmpeg` to locally copy `length` seconds of the video from `start`
+/// on into a chunk.
+pub fn extract_sample(
+    input: &Path,
+    output: &Path,
+    start: f64,
+    length: f64,
+    running: &Arc<AtomicBool>,
+) -> Result<()> {
+    let output =
+        extract_sample_command(input, output, start, length).output()?;
+    if !output.status.success() && running.load(Ordering::SeqCst) {
+        return Err("Failed extracting sample".into());
+    }
+
+    Ok(())
+}
+
+/// Builds the `ffmpeg` command for copying a sample of the video.
+///
+/// Like splitting, it starts at the keyframe before `start`.
+pub fn extract_sample_command(
+    input: &Path,
+    output: &Path,
+    start: f64,
+    length: f64,
+) -> Command {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-y", "-ss", &start.to_string(), "-i"])
+        .arg(input);
+    command.args(["-t", &length.to_string(), "-an", "-c", "copy"]);
+    command.arg(output);
+    command
+}
+
+/// Adds the input to a `command`, preceded by the options that read only the
+/// `range` if there is one.
+///
+/// Seeking on the input starts reading at the keyframe before the start,
+/// which is where a range starts anyway, so the audio and the chunks
+/// copied from the video line up.
+fn add_input(command: &mut Command, input: &Path, range: Option<Range>) {
+    if let Some(range) = range {
+        command.args(range.input_args());
+    }
+    command.arg("-i").arg(input);
+}
+
+/// Returns the extension chunks of the given input will have.
+///
+/// Chunks keep the extension of the input, since they're only copied from it.
+/// Together with their numbered names, this keeps the names of chunks ASCII,
+/// so they can safely be passed to hosts no matter what the input is called.
+pub fn chunk_extension(input: &Path) -> Result<&str> {
+    ascii_extension(input)
+}
+
+/// Returns the extension of a path, which needs to be ASCII.
+pub fn ascii_extension(path: &Path) -> Result<&str> {
+    let extension = path
+        .extension()
+        .ok_or("Unable to find extension")?
+        .to_str()
+        .filter(|extension| extension.is_ascii())
+        .ok_or("Extension needs to be ASCII")?;
+
+    Ok(extension)
+}
+
 /// Uses `ffmpeg` to locally combine the encoded chunks and audio.
+///
+/// If the output is an HLS or DASH package, it's split into segments of
+/// `segment` seconds. With a `range`, the output is cut to its length.
 pub fn combine(
     encoded_dir: &Path,
     audio: &Path,
     output: &Path,
+    segment: u64,
+    range: Option<Range>,
     running: &Arc<AtomicBool>,
 ) -> Result<()> {
-    // Create list of encoded chunks
-    let mut chunks = fs::read_dir(&encoded_dir)?
-        .map(|res| res.and_then(|readdir| Ok(readdir.path())))
+    // Create list of encoded chunks. The concat demuxer resolves relative
+    // paths from the directory of the list, so only the file names go in,
+    // which are ASCII no matter where the temporary directory is.
+    let mut chunks = fs::read_dir(encoded_dir)?
+        .map(|res| res.map(|readdir| readdir.file_name()))
         .map(|res| res.map_err(|e| e.into()))
-        .map(|res| res.and_then(|path| Ok(path.into_os_string())))
         .map(|res| {
             res.and_then(|os_string| {
                 os_string
@@ -93,7 +203,7 @@ pub fn combine(
                     .map_err(|_| "Failed OsString conversion".into())
             })
         })
-        .map(|res| res.and_then(|file| Ok(format!("file '{}'\n", file))))
+        .map(|res| res.map(|file| format!("file '{}'\n", file)))
         .collect::<Result<Vec<String>>>()?;
     // Sort them so we have the right order
     chunks.sort();
@@ -104,28 +214,8 @@ pub fn combine(
     file_list.push("files.txt");
     fs::write(&file_list, chunks)?;
 
-    // Convert paths to &str
-    let audio = audio.to_str().ok_or("Audio invalid Unicode")?;
-    let file_list = file_list.to_str().ok_or("File list invalid Unicode")?;
-    let output = output.to_str().ok_or("Output invalid Unicode")?;
     // Combine everything
-    let output = Command::new("ffmpeg")
-        .args(&[
-            "-y",
-            "-f",
-            "concat",
-            "-safe",
-            "0",
-            "-i",
-            file_list,
-            "-i",
-            audio,
-            "-c",
-            "copy",
-            "-movflags",
-            "+faststart",
-            output,
-        ])
+    let output = combine_command(&file_list, audio, output, segment, range)?
         .output()?;
     if !output.status.success() && running.load(Ordering::SeqCst) {
         return Err("Failed combining video".into());
@@ -133,3 +223,33 @@ pub fn combine(
 
     Ok(())
 }
+
+/// Builds the `ffmpeg` command for combining the encoded chunks listed in
+/// `file_list` and audio.
+pub fn combine_command(
+    file_list: &Path,
+    audio: &Path,
+    output: &Path,
+    segment: u64,
+    range: Option<Range>,
+) -> Result<Command> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .args(["-y", "-f", "concat", "-safe", "0", "-i"])
+        .arg(file_list);
+    command.arg("-i").arg(audio);
+    // The chunks and the audio both start at the start of the range, but
+    // the chunks can end a frame later
+    if let Some(range) = range {
+        command.args(["-t", &range.length().to_string()]);
+    }
+    match Package::from_output(output) {
+        Some(package) => package.add_args(&mut command, output, segment),
+        None => {
+            command
+                .args(["-c", "copy", "-movflags", "+faststart"])
+                .arg(output);
+        }
+    }
+    Ok(command)
+}
@@ -0,0 +1,105 @@
+//! A logger emitting events as JSON for consumption by other programs.
+
+use log::{
+    kv::{self, Key, Value, Visitor},
+    Level, LevelFilter, Log, Metadata, Record, SetLoggerError,
+};
+use serde_json::{Map, Number};
+use std::{io::Write, sync::Mutex};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Writes every log record as a JSON object on its own line.
+///
+/// Besides the timestamp, level and message, the object contains the
+/// key-value pairs of the record, which for the events logged by this crate
+/// are the `stage`, `host`, `chunk`, `duration` (in seconds) and `error`
+/// where applicable. Records at the error level without an explicit `error`
+/// use their message for it.
+pub struct JsonLogger {
+    level: LevelFilter,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLogger {
+    /// Creates a new logger writing records up to `level` to `writer`.
+    pub fn new(level: LevelFilter, writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            level,
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Installs a new logger as the global logger.
+    pub fn init(
+        level: LevelFilter,
+        writer: Box<dyn Write + Send>,
+    ) -> Result<(), SetLoggerError> {
+        log::set_max_level(level);
+        log::set_boxed_logger(Box::new(Self::new(level, writer)))
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut object = Map::new();
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        object.insert("timestamp".into(), timestamp.into());
+        object.insert("level".into(), record.level().as_str().into());
+        object.insert("message".into(), record.args().to_string().into());
+        // Collecting into a map can't fail, so the result is ignored
+        record.key_values().visit(&mut Fields(&mut object)).ok();
+        if record.level() == Level::Error && !object.contains_key("error") {
+            object.insert("error".into(), record.args().to_string().into());
+        }
+
+        if let Ok(mut writer) = self.writer.lock() {
+            // There's nowhere to report failures to, so they're ignored
+            writeln!(writer, "{}", serde_json::Value::Object(object)).ok();
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut writer) = self.writer.lock() {
+            writer.flush().ok();
+        }
+    }
+}
+
+/// Collects the key-value pairs of a record into a JSON object.
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'a, 'kvs> Visitor<'kvs> for Fields<'a> {
+    fn visit_pair(
+        &mut self,
+        key: Key<'kvs>,
+        value: Value<'kvs>,
+    ) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), to_json(&value));
+        Ok(())
+    }
+}
+
+/// Converts a log value to the closest JSON value.
+fn to_json(value: &Value) -> serde_json::Value {
+    if let Some(n) = value.to_u64() {
+        n.into()
+    } else if let Some(n) = value.to_i64() {
+        n.into()
+    } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+        n.into()
+    } else if let Some(b) = value.to_bool() {
+        b.into()
+    } else {
+        value.to_string().into()
+    }
+}
@@ -1,14 +1,24 @@
-use clap::{App, AppSettings, Arg};
-use log::error;
+use clap::{value_t, App, AppSettings, Arg, SubCommand};
+use log::{error, info};
+use shepherd::{
+    parse_time, Budget, ChunkLength, Config, Host, JsonLogger, Metric,
+    Rendition, Sample, Target, Zone,
+};
 use simplelog::{
     ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode,
+    WriteLogger,
+};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
 };
-use std::process;
-
-use shepherd;
 
 fn main() {
-    let matches = App::new(clap::crate_name!())
+    let app = App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .about(
             "A distributed video encoder that splits files into chunks \
@@ -16,113 +26,549 @@ fn main() {
         )
         .author(clap::crate_authors!())
         .setting(AppSettings::TrailingVarArg)
-        .arg(
-            Arg::with_name("clients")
-                .short("c")
-                .long("clients")
-                .value_name("hostnames")
-                .use_delimiter(true)
-                .takes_value(true)
-                .required(true)
-                .help("Comma-separated list of encoding hosts"),
-        )
-        .arg(
-            Arg::with_name("length")
-                .short("l")
-                .long("length")
-                .value_name("seconds")
-                .takes_value(true)
-                .help("The length of video chunks in seconds"),
-        )
-        .arg(
-            Arg::with_name("tmp")
-                .short("t")
-                .long("tmp")
-                .value_name("path")
-                .takes_value(true)
-                .help("The path to the local temporary directory"),
-        )
-        .arg(
-            Arg::with_name("keep")
-                .short("k")
-                .long("keep")
-                .help("Don't clean up temporary files"),
-        )
-        .arg(
-            Arg::with_name("IN")
-                .help("The original video file")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("OUT")
-                .help("The output video file")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("ffmpeg")
-                .value_name("FFMPEG OPTIONS")
-                .multiple(true)
-                .help(
-                    "Options/flags for ffmpeg encoding of chunks. The\n\
-                     chunks are video only, so don't pass in anything\n\
-                     concerning audio. Input/output file names are added\n\
-                     by the application, so there is no need for that\n\
-                     either. This is the last positional argument and\n\
-                     needs to be preceeded by double hypens (--) as in:\n\
-                     shepherd -c c1,c2 in.mp4 out.mp4 -- -c:v libx264\n\
-                     -crf 26 -preset veryslow -profile:v high -level 4.2\n\
-                     -pix_fmt yuv420p\n\
-                     This is also the default that is used if no options\n\
-                     are provided.",
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&args())
+        .subcommand(
+            SubCommand::with_name("sample")
+                .about(
+                    "Encodes evenly spaced samples of IN to tell how fast \
+                     every host is and how long and big the whole job \
+                     would be, without writing OUT",
+                )
+                .setting(AppSettings::TrailingVarArg)
+                .args(&args())
+                .arg(
+                    Arg::with_name("samples")
+                        .long("samples")
+                        .value_name("count")
+                        .takes_value(true)
+                        .default_value("5")
+                        .validator(|v| parse_count(&v).map(|_| ()))
+                        .help("The number of samples"),
+                )
+                .arg(
+                    Arg::with_name("sample-length")
+                        .long("sample-length")
+                        .value_name("seconds")
+                        .takes_value(true)
+                        .default_value("10")
+                        .validator(|v| match parse_time(&v) {
+                            Ok(length) if length > 0.0 => Ok(()),
+                            _ => Err(format!("Invalid sample length {}", v)),
+                        })
+                        .help("The length of every sample in seconds"),
+                )
+                .arg(
+                    Arg::with_name("keep-samples").long("keep-samples").help(
+                        "Keep the encoded samples next to OUT, numbered \
+                             as in out_sample_01.mp4",
+                    ),
                 ),
         )
         .get_matches();
+    // Sampling takes the same arguments as a job, and some of its own
+    let matches = app.subcommand_matches("sample").unwrap_or(&app);
     // If we get here, unwrap is safe on mandatory arguments
-    let input = matches.value_of("IN").unwrap();
-    let output = matches.value_of("OUT").unwrap();
-    let hosts = matches.values_of("clients").unwrap().collect();
-    let seconds = matches.value_of("length");
-    let tmp = matches.value_of("tmp");
-    let keep = matches.is_present("keep");
-    // Take the given arguments for ffmpeg or use the defaults
-    let args: Vec<&str> = matches
-        .values_of("ffmpeg")
-        .map(|a| a.collect())
-        .unwrap_or_else(|| {
-            vec![
-                "-c:v",
-                "libx264",
-                "-crf",
-                "26",
-                "-preset",
-                "veryslow",
-                "-profile:v",
-                "high",
-                "-level",
-                "4.2",
-                "-pix_fmt",
-                "yuv420p",
-            ]
-        });
+    // Paths don't have to be valid Unicode, so take them as they are
+    let input = matches.value_of_os("IN").unwrap();
+    let output = matches.value_of_os("OUT").unwrap_or_default();
+    // Validators ran, so parsing can't fail
+    let mut hosts: Vec<Host> = matches
+        .values_of("clients")
+        .map(|hosts| hosts.map(|host| host.parse().unwrap()).collect())
+        .unwrap_or_default();
+    if let Some(path) = matches.value_of_os("hosts").map(Path::new) {
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|lines| Host::from_lines(&lines))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed reading hosts {}: {}", path.display(), e);
+                process::exit(1);
+            });
+        hosts.extend(parsed);
+    }
+    let mut config = Config::new(input, output, hosts);
+    if let Some(path) = matches.value_of_os("renditions").map(Path::new) {
+        config.renditions = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|lines| Rendition::from_lines(&lines))
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "Failed reading renditions {}: {}",
+                    path.display(),
+                    e
+                );
+                process::exit(1);
+            });
+    }
+    if let Some(path) = matches.value_of_os("zones").map(Path::new) {
+        config.zones = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|lines| Zone::from_lines(&lines))
+            .unwrap_or_else(|e| {
+                eprintln!("Failed reading zones {}: {}", path.display(), e);
+                process::exit(1);
+            });
+    }
+    // Validators ran, so parsing can't fail
+    if let Some(length) = matches.value_of("length") {
+        config.length = length.parse().unwrap();
+    }
+    if let Some(count) = matches.value_of("chunks") {
+        config.length = ChunkLength::Count(parse_count(count).unwrap());
+    }
+    if let Some(size) = matches.value_of("chunk-size") {
+        config.length = ChunkLength::Size(parse_size(size).unwrap());
+    }
+    config.start = matches.value_of("start").map(|t| parse_time(t).unwrap());
+    config.end = matches.value_of("end").map(|t| parse_time(t).unwrap());
+    if matches.is_present("segment") {
+        config.segment =
+            value_t!(matches, "segment", u64).unwrap_or_else(|e| e.exit());
+    }
+    config.tmp_dir = matches.value_of_os("tmp").map(PathBuf::from);
+    config.keep = matches.is_present("keep");
+    config.verify = !matches.is_present("no-verify");
+    // Defaults are set and validators ran, so parsing can't fail
+    if app.subcommand_matches("sample").is_some() {
+        let mut sample = Sample::new(
+            parse_count(matches.value_of("samples").unwrap()).unwrap(),
+            parse_time(matches.value_of("sample-length").unwrap()).unwrap(),
+        );
+        sample.keep = matches.is_present("keep-samples");
+        config.sample = Some(sample);
+    }
+    // Possible values are restricted, so parsing can't fail
+    config.metrics = matches
+        .values_of("metrics")
+        .map(|m| m.map(|m| m.parse().unwrap()).collect())
+        .unwrap_or_default();
+    // Validators ran, so parsing can't fail
+    config.target = matches.value_of("target-quality").map(|target| {
+        let (metric, score) = parse_target(target).unwrap();
+        let mut target = Target::new(metric, score);
+        if let Some(range) = matches.value_of("crf-range") {
+            target.crf_range = parse_range(range).unwrap();
+        }
+        if matches.is_present("probes") {
+            target.probes =
+                value_t!(matches, "probes", u32).unwrap_or_else(|e| e.exit());
+        }
+        target
+    });
+    if let Some(size) = matches.value_of("target-size") {
+        config.budget = Some(Budget::Size(parse_size(size).unwrap()));
+    }
+    if let Some(rate) = matches.value_of("target-bitrate") {
+        config.budget = Some(Budget::BitRate(parse_bit_rate(rate).unwrap()));
+    }
+    // Take the given arguments for ffmpeg or stay with the defaults
+    if let Some(args) = matches.values_of("ffmpeg") {
+        config.args = args.map(ToString::to_string).collect();
+    }
+    let dry_run = matches.is_present("dry-run");
+    let log_format = matches.value_of("log-format").unwrap();
+    let log_file = matches.value_of_os("log-file").map(Path::new);
 
-    TermLogger::init(
-        LevelFilter::Info,
-        ConfigBuilder::new()
-            .set_time_offset_to_local()
-            .expect("Unable to determine time offset")
-            .build(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )
+    // Open the log file if there is one, otherwise we log to stderr
+    let log_writer: Option<Box<dyn Write + Send>> = log_file.map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("Failed creating log file {}: {}", path.display(), e);
+            process::exit(1);
+        });
+        Box::new(file) as Box<dyn Write + Send>
+    });
+    let log_config = ConfigBuilder::new()
+        .set_time_offset_to_local()
+        .expect("Unable to determine time offset")
+        .build();
+    match (log_format, log_writer) {
+        ("json", writer) => JsonLogger::init(
+            LevelFilter::Info,
+            writer.unwrap_or_else(|| Box::new(io::stderr())),
+        ),
+        (_, Some(writer)) => {
+            WriteLogger::init(LevelFilter::Info, log_config, writer)
+        }
+        (_, None) => TermLogger::init(
+            LevelFilter::Info,
+            log_config,
+            TerminalMode::Mixed,
+            ColorChoice::Auto,
+        ),
+    }
     .expect("Failed initializing logger");
 
+    if dry_run {
+        match shepherd::dry_run(&config) {
+            Ok(plan) => print!("{}", plan),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Set up a shared boolean to check whether the user has aborted
+    let running = Arc::new(AtomicBool::new(true));
+    let r = Arc::clone(&running);
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+        info!(
+            "Abort signal received. Waiting for remote encoders to finish the \
+             current chunk and quit gracefully."
+        );
+    })
+    .expect("Error setting Ctrl-C handler");
+
     if cfg!(debug_assertions) {
-        shepherd::run(input, output, &args, hosts, seconds, tmp, keep)
-            .unwrap();
-    } else if let Err(e) =
-        shepherd::run(input, output, &args, hosts, seconds, tmp, keep)
-    {
+        shepherd::run(&config, running).unwrap();
+    } else if let Err(e) = shepherd::run(&config, running) {
         error!("{}", e);
         process::exit(1);
     }
 }
+
+/// Returns the arguments shared by a job and sampling it.
+fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("clients")
+            .short("c")
+            .long("clients")
+            .value_name("hostnames")
+            .use_delimiter(true)
+            .takes_value(true)
+            .required_unless("hosts")
+            .validator(|v| v.parse::<Host>().map(|_| ()))
+            .help(
+                "Comma-separated list of encoding hosts, each optionally \
+                 followed by the number of chunks to encode on it at \
+                 the same time as in bigbox:4",
+            ),
+        Arg::with_name("hosts")
+            .short("H")
+            .long("hosts")
+            .value_name("path")
+            .takes_value(true)
+            .help(
+                "File with an encoding host per line, optionally \
+                 followed by SSH options as in: bigbox:4 user=me \
+                 port=2222 identity=~/.ssh/id_big jump=bastion power=2 \
+                 option=Compression=yes",
+            ),
+        Arg::with_name("renditions")
+            .short("R")
+            .long("renditions")
+            .value_name("path")
+            .takes_value(true)
+            .help(
+                "File with a rendition per line to encode instead of \
+                 OUT, given by name, scale (- for none), output and \
+                 optionally ffmpeg options as in: 720p -2:720 \
+                 out_720p.mp4 -c:v libx264 -crf 24",
+            ),
+        Arg::with_name("zones")
+            .short("Z")
+            .long("zones")
+            .value_name("path")
+            .takes_value(true)
+            .help(
+                "File with a time range per line to encode with other \
+                 ffmpeg options, given by start, end (- for the end of \
+                 IN) and the options that replace or add to the others \
+                 as in: 1:25:00 - -crf 32",
+            ),
+        Arg::with_name("length")
+            .short("l")
+            .long("length")
+            .value_name("seconds")
+            .takes_value(true)
+            .validator(|v| v.parse::<ChunkLength>().map(|_| ()))
+            .help(
+                "The length of video chunks in seconds, or auto to pick \
+                 one from the duration of IN and the hosts",
+            ),
+        Arg::with_name("chunks")
+            .long("chunks")
+            .value_name("count")
+            .takes_value(true)
+            .conflicts_with("length")
+            .validator(|v| parse_count(&v).map(|_| ()))
+            .help("Split the video into this many chunks instead"),
+        Arg::with_name("chunk-size")
+            .long("chunk-size")
+            .value_name("size")
+            .takes_value(true)
+            .conflicts_with_all(&["length", "chunks"])
+            .validator(|v| parse_size(&v).map(|_| ()))
+            .help(
+                "Split the video into chunks of about this size instead, \
+                 in bytes or with a suffix as in 200M",
+            ),
+        Arg::with_name("start")
+            .long("start")
+            .value_name("time")
+            .takes_value(true)
+            .validator(|v| parse_time(&v).map(|_| ()))
+            .help(
+                "Where to start encoding IN, in seconds or as in 1:30 \
+                 or 1:02:03.5",
+            ),
+        Arg::with_name("end")
+            .long("end")
+            .value_name("time")
+            .takes_value(true)
+            .validator(|v| parse_time(&v).map(|_| ()))
+            .help("Where to stop encoding IN, given like the start"),
+        Arg::with_name("segment")
+            .long("segment")
+            .value_name("seconds")
+            .takes_value(true)
+            .validator(|v| match v.parse::<u64>() {
+                Ok(length) if length > 0 => Ok(()),
+                _ => Err(format!("Invalid segment length {}", v)),
+            })
+            .help(
+                "The segment length in seconds if OUT is an HLS (.m3u8) \
+                 or DASH (.mpd) package [6]",
+            ),
+        Arg::with_name("tmp")
+            .short("t")
+            .long("tmp")
+            .value_name("path")
+            .takes_value(true)
+            .help("The path to the local temporary directory"),
+        Arg::with_name("metrics")
+            .short("m")
+            .long("metrics")
+            .value_name("metrics")
+            .use_delimiter(true)
+            .takes_value(true)
+            .possible_values(&["ssim", "psnr", "vmaf"])
+            .help(
+                "Comma-separated list of quality metrics to compute for \
+                 every chunk",
+            ),
+        Arg::with_name("target-quality")
+            .long("target-quality")
+            .value_name("metric=score")
+            .takes_value(true)
+            .validator(|v| parse_target(&v).map(|_| ()))
+            .help(
+                "Pick the CRF for every chunk so it reaches this score, \
+                 e.g. vmaf=93",
+            ),
+        Arg::with_name("crf-range")
+            .long("crf-range")
+            .value_name("min-max")
+            .takes_value(true)
+            .requires("target-quality")
+            .validator(|v| parse_range(&v).map(|_| ()))
+            .help("The CRFs to search for the target quality [18-38]"),
+        Arg::with_name("probes")
+            .long("probes")
+            .value_name("count")
+            .takes_value(true)
+            .requires("target-quality")
+            .validator(|v| match v.parse::<u32>() {
+                Ok(probes) if probes > 0 => Ok(()),
+                _ => Err(format!("Invalid number of probes {}", v)),
+            })
+            .help(
+                "The maximum number of probe encodes per chunk for the \
+                 target quality [4]",
+            ),
+        Arg::with_name("target-size")
+            .long("target-size")
+            .value_name("size")
+            .takes_value(true)
+            .conflicts_with("target-quality")
+            .validator(|v| parse_size(&v).map(|_| ()))
+            .help(
+                "Encode in two passes so OUT ends up about this size, in \
+                 bytes or with a suffix as in 700M",
+            ),
+        Arg::with_name("target-bitrate")
+            .long("target-bitrate")
+            .value_name("rate")
+            .takes_value(true)
+            .conflicts_with_all(&["target-quality", "target-size"])
+            .validator(|v| parse_bit_rate(&v).map(|_| ()))
+            .help(
+                "Encode in two passes so the video of OUT has this average \
+                 bitrate, in bits per second or with a suffix as in 2500k",
+            ),
+        Arg::with_name("log-format")
+            .long("log-format")
+            .value_name("format")
+            .takes_value(true)
+            .possible_values(&["term", "json"])
+            .default_value("term")
+            .help("The format of log messages"),
+        Arg::with_name("log-file")
+            .long("log-file")
+            .value_name("path")
+            .takes_value(true)
+            .help("Write log messages to a file instead of stderr"),
+        Arg::with_name("keep")
+            .short("k")
+            .long("keep")
+            .help("Don't clean up temporary files"),
+        Arg::with_name("no-verify").long("no-verify").help(
+            "Don't check the encoded chunks and the output against the \
+         source",
+        ),
+        Arg::with_name("dry-run")
+            .short("n")
+            .long("dry-run")
+            .help("Print the execution plan without touching any host"),
+        Arg::with_name("IN")
+            .help("The original video file")
+            .required(true),
+        Arg::with_name("OUT")
+            .help(
+                "The output video file, or the playlist of an HLS \
+                 (.m3u8) or DASH (.mpd) package",
+            )
+            .required_unless("renditions"),
+        Arg::with_name("ffmpeg")
+            .value_name("FFMPEG OPTIONS")
+            .multiple(true)
+            .last(true)
+            .help(
+                "Options/flags for ffmpeg encoding of chunks. The\n\
+                 chunks are video only, so don't pass in anything\n\
+                 concerning audio. Input/output file names are added\n\
+                 by the application, so there is no need for that\n\
+                 either. This is the last positional argument and\n\
+                 needs to be preceeded by double hypens (--) as in:\n\
+                 shepherd -c c1,c2 in.mp4 out.mp4 -- -c:v libx264\n\
+                 -crf 26 -preset veryslow -profile:v high -level 4.2\n\
+                 -pix_fmt yuv420p\n\
+                 This is also the default that is used if no options\n\
+                 are provided. {index}, {start}, {duration} and {host}\n\
+                 are replaced with those of every chunk.",
+            ),
+    ]
+}
+
+/// Parses a target quality of the form `metric=score`.
+fn parse_target(target: &str) -> Result<(Metric, f64), String> {
+    let (metric, score) = target
+        .split_once('=')
+        .ok_or("Target quality needs to be of the form metric=score")?;
+    let metric = metric.parse()?;
+    let score = score
+        .parse()
+        .map_err(|_| format!("Invalid target score {}", score))?;
+
+    Ok((metric, score))
+}
+
+/// Parses a CRF range of the form `min-max`.
+fn parse_range(range: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid CRF range {}", range);
+    let (min, max) = range.split_once('-').ok_or_else(invalid)?;
+    let min = min.parse().map_err(|_| invalid())?;
+    let max = max.parse().map_err(|_| invalid())?;
+    if min > max {
+        return Err(invalid());
+    }
+
+    Ok((min, max))
+}
+
+/// Parses a positive number of chunks.
+fn parse_count(count: &str) -> Result<u32, String> {
+    count
+        .parse()
+        .ok()
+        .filter(|&count| count > 0)
+        .ok_or_else(|| format!("Invalid number of chunks {}", count))
+}
+
+/// Parses a size in bytes, optionally followed by one of the binary suffixes
+/// `K`, `M` or `G` as in `200M`.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid size {}", size);
+    let (number, factor) = match size.char_indices().last() {
+        Some((i, 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    let bytes = number.parse::<f64>().map_err(|_| invalid())? * factor as f64;
+    // Casting saturates, so infinite sizes would pass as the largest one
+    if !bytes.is_finite() || bytes < 1.0 {
+        return Err(invalid());
+    }
+
+    Ok(bytes as u64)
+}
+
+/// Parses a bitrate in bits per second, optionally followed by one of the
+/// decimal suffixes `k` or `M` as in `2500k`.
+fn parse_bit_rate(rate: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid bitrate {}", rate);
+    let (number, factor) = match rate.char_indices().last() {
+        Some((i, 'k')) => (&rate[..i], 1e3),
+        Some((i, 'M')) => (&rate[..i], 1e6),
+        _ => (rate, 1.0),
+    };
+    let bits = number.parse::<f64>().map_err(|_| invalid())? * factor;
+    if !bits.is_finite() || bits < 1.0 {
+        return Err(invalid());
+    }
+
+    Ok(bits as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("200M"), Ok(200 << 20));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in ["", "M", "0", "0.1", "-5M", "2T", "infM", "NaN", "1e400"]
+        {
+            assert_eq!(
+                parse_size(size),
+                Err(format!("Invalid size {}", size))
+            );
+        }
+    }
+
+    #[test]
+    fn parses_bit_rates() {
+        assert_eq!(parse_bit_rate("800000"), Ok(800_000));
+        assert_eq!(parse_bit_rate("2500k"), Ok(2_500_000));
+        assert_eq!(parse_bit_rate("1.5M"), Ok(1_500_000));
+    }
+
+    #[test]
+    fn rejects_invalid_bit_rates() {
+        for rate in ["", "k", "0", "0.5", "-2M", "2K", "infk", "NaN"] {
+            assert_eq!(
+                parse_bit_rate(rate),
+                Err(format!("Invalid bitrate {}", rate))
+            );
+        }
+    }
+
+    #[test]
+    fn parses_chunk_counts() {
+        assert_eq!(parse_count("12"), Ok(12));
+        assert!(parse_count("0").is_err());
+        assert!(parse_count("-1").is_err());
+        assert!(parse_count("1.5").is_err());
+    }
+}
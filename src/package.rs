@@ -0,0 +1,140 @@
+//! Functions for packaging the encoded chunks for streaming.
+
+use std::{path::Path, process::Command};
+
+/// The segment length in seconds if none is given.
+pub const DEFAULT_SEGMENT: u64 = 6;
+
+/// A streaming format the output can be packaged in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Package {
+    /// HLS with fMP4 segments and a separate audio rendition.
+    Hls,
+    /// DASH with separate video and audio adaptation sets.
+    Dash,
+}
+
+impl Package {
+    /// Returns the package the output should be, going by its extension, or
+    /// `None` for a regular file.
+    pub fn from_output(output: &Path) -> Option<Self> {
+        match output.extension()?.to_str()? {
+            "m3u8" => Some(Self::Hls),
+            "mpd" => Some(Self::Dash),
+            _ => None,
+        }
+    }
+
+    /// Returns the `ffmpeg` arguments that put a keyframe at every segment
+    /// boundary of the output into a chunk starting `position` seconds into
+    /// it.
+    ///
+    /// The muxers cut the segments on a grid counted from the start of the
+    /// output, so the keyframes are shifted by where the chunk is on it.
+    /// Every chunk starts with a keyframe anyway, which takes the first one.
+    pub fn keyframe_args(segment: u64, position: f64) -> Vec<String> {
+        let millis = segment * 1000;
+        let phase = (position * 1000.0).round() as u64 % millis;
+        let boundary = match phase {
+            0 => format!("n_forced*{}", segment),
+            _ => format!("n_forced*{}-{}", segment, phase as f64 / 1000.0),
+        };
+        vec![
+            "-force_key_frames".to_string(),
+            format!("expr:gte(t,{})", boundary),
+        ]
+    }
+
+    /// Adds the arguments for muxing into the package to the combining
+    /// `command`, which has the video as its first input and the audio as
+    /// its second.
+    pub fn add_args(self, command: &mut Command, output: &Path, segment: u64) {
+        command.args(["-map", "0:v", "-map", "1:a", "-c", "copy"]);
+        match self {
+            Self::Hls => {
+                // The output is the master playlist, every rendition gets its
+                // own playlist and segments next to it
+                let stem = output.file_stem().unwrap_or_default();
+                // Paths don't need to be UTF-8, so neither do the names
+                let named = |suffix| {
+                    let mut name = stem.to_os_string();
+                    name.push(suffix);
+                    name
+                };
+                let sibling = |suffix| output.with_file_name(named(suffix));
+                command.args([
+                    "-f",
+                    "hls",
+                    "-hls_time",
+                    &segment.to_string(),
+                    "-hls_playlist_type",
+                    "vod",
+                    "-hls_segment_type",
+                    "fmp4",
+                    "-hls_flags",
+                    "independent_segments",
+                    "-hls_fmp4_init_filename",
+                ]);
+                command.arg(named("_%v_init.mp4"));
+                command.arg("-hls_segment_filename");
+                command.arg(sibling("_%v_%05d.m4s"));
+                command
+                    .arg("-master_pl_name")
+                    .arg(output.file_name().unwrap_or_default());
+                command.args([
+                    "-var_stream_map",
+                    "v:0,agroup:audio a:0,agroup:audio,default:yes",
+                ]);
+                command.arg(sibling("_%v.m3u8"));
+            }
+            Self::Dash => {
+                command.args([
+                    "-f",
+                    "dash",
+                    "-seg_duration",
+                    &segment.to_string(),
+                    "-use_template",
+                    "1",
+                    "-use_timeline",
+                    "1",
+                    "-adaptation_sets",
+                    "id=0,streams=v id=1,streams=a",
+                ]);
+                command.arg(output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyframes_follow_the_segments_of_the_output() {
+        let expr = |position| Package::keyframe_args(6, position)[1].clone();
+        assert_eq!(expr(0.0), "expr:gte(t,n_forced*6)");
+        assert_eq!(expr(12.0), "expr:gte(t,n_forced*6)");
+        assert_eq!(expr(40.01), "expr:gte(t,n_forced*6-4.01)");
+        assert_eq!(expr(17.9996), "expr:gte(t,n_forced*6)");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hls_names_keep_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let output = Path::new(OsStr::from_bytes(b"/videos/caf\xe9.m3u8"));
+        let mut command = Command::new("ffmpeg");
+        Package::Hls.add_args(&mut command, output, 6);
+        let args: Vec<&OsStr> = command.get_args().collect();
+        for expected in [
+            &b"caf\xe9_%v_init.mp4"[..],
+            b"/videos/caf\xe9_%v_%05d.m4s",
+            b"caf\xe9.m3u8",
+            b"/videos/caf\xe9_%v.m3u8",
+        ] {
+            assert!(args.contains(&OsStr::from_bytes(expected)));
+        }
+    }
+}
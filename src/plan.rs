@@ -0,0 +1,578 @@
+//! Functions for describing what a job would do without doing it.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use super::{
+    budget, checksum, control, local, quality, remote, sample, shell::quote,
+    template::Span, verify, zone, ChunkLength, Config, Host, MediaInfo,
+    Rendition, Result, AUDIO,
+};
+
+/// Builds a human-readable execution plan for a job.
+///
+/// The input is probed locally to compute the expected chunk boundaries, but
+/// no hosts are contacted and nothing is written to disk.
+pub fn describe(config: &Config, tmp_dir: &Path) -> Result<String> {
+    let input = config.input.as_path();
+    let mut info = MediaInfo::probe(input)?;
+    let described = info.to_string();
+    let range = config.range(&mut info)?;
+    // Samples are the chunks when sampling
+    let seconds = match config.sample {
+        Some(sample) => sample.length,
+        None => config.chunk_length(&info)?,
+    };
+    let warnings = info.check(seconds)?;
+    let duration = info.duration;
+    let average = config
+        .budget
+        .map(|budget| budget.video_bit_rate(&info))
+        .transpose()?;
+    let extension = local::chunk_extension(input)?;
+    // Reconstruct the paths of the local temporary files
+    let audio = tmp_dir.join(AUDIO);
+    let chunk_dir = tmp_dir.join("chunks");
+    let encoded_dir = tmp_dir.join("encoded");
+
+    // Compute the chunk names and boundaries the segment muxer will aim for,
+    // in the time of the input
+    let offset = range.map_or(0.0, |range| range.start);
+    let cuts = Some(&config.zones)
+        .filter(|zones| !zones.is_empty())
+        .map(|zones| zone::cuts(zones, seconds, offset, duration));
+    let chunk_name =
+        |i| chunk_dir.join(format!("chunk_{:03}.{}", i, extension));
+    let chunks: Vec<(PathBuf, f64, f64)> = match config.sample {
+        Some(sample) => sample
+            .starts(offset, duration)
+            .into_iter()
+            .enumerate()
+            .map(|(i, start)| {
+                let end = (start + seconds).min(offset + duration);
+                (chunk_name(i), start, end)
+            })
+            .collect(),
+        None => {
+            // Zones have the chunks start and end with them
+            let starts: Vec<f64> = match &cuts {
+                Some(cuts) => {
+                    Some(0.0).into_iter().chain(cuts.clone()).collect()
+                }
+                None => (0..)
+                    .map(|i| i as f64 * seconds)
+                    .take_while(|&start| start < duration)
+                    .collect(),
+            };
+            starts
+                .iter()
+                .enumerate()
+                .map(|(i, &start)| {
+                    let end = starts.get(i + 1).copied().unwrap_or(duration);
+                    (chunk_name(i), offset + start, offset + end)
+                })
+                .collect()
+        }
+    };
+
+    // Writing to a String can't fail, so the results are ignored throughout
+    let mut plan = String::new();
+    writeln!(plan, "Input: {} ({})", input.display(), described).ok();
+    if let Some(range) = range {
+        write!(plan, "Range: {}", range).ok();
+        if let Some(start) = config.start.filter(|&start| start > range.start)
+        {
+            write!(plan, " (moved back from {:.2} s to a keyframe)", start)
+                .ok();
+        }
+        writeln!(plan).ok();
+    }
+    for warning in &warnings {
+        writeln!(plan, "Warning: {}", warning).ok();
+    }
+    for rendition in &config.renditions {
+        if config.renditions.len() > 1 {
+            writeln!(
+                plan,
+                "Output {}: {}",
+                rendition.name,
+                rendition.output.display()
+            )
+            .ok();
+        } else {
+            writeln!(plan, "Output: {}", rendition.output.display()).ok();
+        }
+    }
+    writeln!(
+        plan,
+        "Job: {} (every run picks a new ID for its temporary directories)",
+        config.job
+    )
+    .ok();
+    writeln!(plan, "Temporary directory: {}", tmp_dir.display()).ok();
+    writeln!(
+        plan,
+        "Control socket: {}",
+        tmp_dir.join(control::SOCKET).display()
+    )
+    .ok();
+    writeln!(plan).ok();
+    if config.sample.is_none()
+        && !matches!(config.length, ChunkLength::Seconds(_))
+    {
+        writeln!(
+            plan,
+            "Picked a chunk length of {:.2} s for {}",
+            seconds,
+            config.length.goal()
+        )
+        .ok();
+    }
+    for (number, zone) in config.zones.iter().enumerate() {
+        writeln!(
+            plan,
+            "Zone {}: {} with {}",
+            number + 1,
+            zone,
+            zone.args.join(" ")
+        )
+        .ok();
+    }
+    if let (Some(budget), Some(average)) = (config.budget, average) {
+        writeln!(
+            plan,
+            "Aiming for {} with {} kb/s of video on average (chunks get more \
+             or less by their complexity after the first pass)",
+            budget,
+            average / 1000
+        )
+        .ok();
+    }
+    if config.sample.is_some() {
+        writeln!(
+            plan,
+            "Sampling {} excerpts of {} s (each starts at the keyframe \
+             before its start):",
+            chunks.len(),
+            seconds
+        )
+        .ok();
+    } else {
+        writeln!(
+            plan,
+            "Expected {} chunks of {}{} s (the actual cuts happen at the \
+             first keyframe after each boundary):",
+            chunks.len(),
+            if cuts.is_some() { "up to " } else { "" },
+            seconds
+        )
+        .ok();
+    }
+    for (index, (chunk, start, end)) in chunks.iter().enumerate() {
+        let span = Span {
+            index,
+            start: *start,
+            duration: end - start,
+            offset,
+        };
+        let zone = config
+            .zones
+            .iter()
+            .position(|zone| zone.contains(span))
+            .map(|number| format!("  zone {}", number + 1))
+            .unwrap_or_default();
+        writeln!(
+            plan,
+            "    {}  {:>10.2} - {:>10.2}{}",
+            file_name(chunk),
+            start,
+            end,
+            zone
+        )
+        .ok();
+    }
+
+    let mut step = 1;
+    if config.sample.is_some() {
+        // Samples aren't combined, so there's no audio
+        writeln!(plan, "\n{}. Extract samples", step).ok();
+        step += 1;
+        for (chunk, start, _) in &chunks {
+            writeln!(
+                plan,
+                "    {}",
+                command_line(&local::extract_sample_command(
+                    input, chunk, *start, seconds
+                ))
+            )
+            .ok();
+        }
+    } else {
+        writeln!(plan, "\n{}. Extract audio", step).ok();
+        step += 1;
+        writeln!(
+            plan,
+            "    {}",
+            command_line(&local::extract_audio_command(
+                input,
+                &audio,
+                info.audio_bit_rate(),
+                range
+            )?)
+        )
+        .ok();
+
+        writeln!(plan, "\n{}. Split video", step).ok();
+        step += 1;
+        writeln!(
+            plan,
+            "    {}",
+            command_line(&local::split_video_command(
+                input,
+                &chunk_dir,
+                Duration::from_secs_f64(seconds),
+                cuts.as_deref(),
+                range
+            )?)
+        )
+        .ok();
+    }
+
+    writeln!(plan, "\n{}. Encode on hosts", step).ok();
+    step += 1;
+    // Hosts take chunks from a shared queue, so which host encodes which
+    // chunk is only decided at runtime. Show the commands for the first.
+    if let Some((chunk, start, end)) = chunks.first() {
+        let dir = remote::tmp_dir(&config.job);
+        let span = Span {
+            index: 0,
+            start: *start,
+            duration: end - start,
+            offset,
+        };
+        for host in &config.hosts {
+            if host.slots > 1 {
+                writeln!(plan, "    {} ({} encoder slots):", host, host.slots)
+                    .ok();
+            } else {
+                writeln!(plan, "    {}:", host).ok();
+            }
+            writeln!(
+                plan,
+                "        {}",
+                command_line(&remote::mkdir_command(host, &dir))
+            )
+            .ok();
+            writeln!(
+                plan,
+                "        For every chunk it receives, e.g. {}:",
+                file_name(chunk)
+            )
+            .ok();
+            for command in &[
+                remote::transfer_command(host, &dir, chunk),
+                checksum::remote_hash_command(
+                    host,
+                    &remote::remote_name(&dir, chunk),
+                ),
+            ] {
+                writeln!(plan, "            {}", command_line(command)).ok();
+            }
+            for rendition in &config.renditions {
+                let indent = if config.renditions.len() > 1 {
+                    writeln!(plan, "            For {}:", rendition.name).ok();
+                    "                "
+                } else {
+                    "            "
+                };
+                // Show the arguments as they'd be for the chunk on this host
+                let expanded =
+                    config.chunk_rendition(rendition, span, &host.name);
+                let rendition = &expanded;
+                // Two-pass encodes show the first pass, then the second as
+                // an encode with its options
+                let mut second;
+                let rendition = match average {
+                    Some(average) => {
+                        let passlog =
+                            budget::passlog(&dir, chunk, &rendition.name);
+                        writeln!(
+                            plan,
+                            "{}First pass at {} kb/s, which every chunk gets \
+                             before the second:",
+                            indent,
+                            average / 1000
+                        )
+                        .ok();
+                        let args = budget::pass_args(
+                            &rendition.encode_args(),
+                            average,
+                            1,
+                            &passlog,
+                        );
+                        for command in &[
+                            budget::first_pass_command(
+                                host, &dir, chunk, &args,
+                            ),
+                            budget::stats_command(host, &passlog),
+                        ] {
+                            writeln!(
+                                plan,
+                                "{}    {}",
+                                indent,
+                                command_line(command)
+                            )
+                            .ok();
+                        }
+                        writeln!(
+                            plan,
+                            "{}Second pass at the chunk's own bitrate, e.g. \
+                             the average (after the first pass again if \
+                             another host did it):",
+                            indent
+                        )
+                        .ok();
+                        second = rendition.clone();
+                        second.args = budget::pass_args(
+                            &rendition.args,
+                            average,
+                            2,
+                            &passlog,
+                        );
+                        &second
+                    }
+                    None => rendition,
+                };
+                describe_encode(
+                    &mut plan,
+                    config,
+                    host,
+                    chunk,
+                    rendition,
+                    &encoded_dir.join(&rendition.name),
+                    indent,
+                )?;
+            }
+        }
+    }
+
+    if let Some(sample) = config.sample {
+        writeln!(
+            plan,
+            "\n{}. Report the speed of every host and project the time and \
+             size of the whole job",
+            step
+        )
+        .ok();
+        step += 1;
+        if sample.keep {
+            for rendition in &config.renditions {
+                let out_ext = super::output_extension(&rendition.output)?;
+                writeln!(
+                    plan,
+                    "    Keep the encoded samples as {} and so on",
+                    sample::kept_path(&rendition.output, &out_ext, 0)
+                        .display()
+                )
+                .ok();
+            }
+        }
+        return Ok(describe_cleanup(plan, config, tmp_dir, step));
+    }
+
+    writeln!(plan, "\n{}. Combine encoded chunks and audio", step).ok();
+    step += 1;
+    for rendition in &config.renditions {
+        let file_list = encoded_dir.join(&rendition.name).join("files.txt");
+        writeln!(
+            plan,
+            "    {}",
+            command_line(&local::combine_command(
+                &file_list,
+                &audio,
+                &rendition.output,
+                config.segment,
+                range
+            )?)
+        )
+        .ok();
+    }
+
+    if config.verify {
+        writeln!(plan, "\n{}. Verify output against the source", step).ok();
+        step += 1;
+        // With a range, the output is checked against the chunks alone
+        let source = match range {
+            Some(_) => chunks.first().map(|(chunk, _, _)| chunk.as_path()),
+            None => Some(input),
+        };
+        if let Some(source) = source {
+            writeln!(
+                plan,
+                "    {}",
+                command_line(&verify::probe_command(source, false))
+            )
+            .ok();
+        }
+        writeln!(
+            plan,
+            "    The same for every chunk and its encoded versions"
+        )
+        .ok();
+        for rendition in &config.renditions {
+            writeln!(
+                plan,
+                "    {}",
+                command_line(&verify::probe_command(&rendition.output, false))
+            )
+            .ok();
+        }
+    }
+
+    Ok(describe_cleanup(plan, config, tmp_dir, step))
+}
+
+/// Finishes the plan with the cleanup as the given step.
+fn describe_cleanup(
+    mut plan: String,
+    config: &Config,
+    tmp_dir: &Path,
+    step: u32,
+) -> String {
+    writeln!(plan, "\n{}. Clean up", step).ok();
+    let dir = remote::tmp_dir(&config.job);
+    for host in &config.hosts {
+        if !config.keep {
+            writeln!(
+                plan,
+                "    {}",
+                command_line(&remote::cleanup_command(host, &dir))
+            )
+            .ok();
+        }
+        writeln!(plan, "    {}", command_line(&remote::close_command(host)))
+            .ok();
+    }
+    if !config.keep {
+        writeln!(plan, "    rm -r {}", quote(&tmp_dir.to_string_lossy())).ok();
+    }
+
+    plan
+}
+
+/// Describes the commands encoding a chunk for a rendition on a host, after
+/// it has been transferred there, up to returning it.
+fn describe_encode(
+    plan: &mut String,
+    config: &Config,
+    host: &Host,
+    chunk: &Path,
+    rendition: &Rendition,
+    rendition_dir: &Path,
+    indent: &str,
+) -> Result<()> {
+    let dir = remote::tmp_dir(&config.job);
+    let out_ext = super::output_extension(&rendition.output)?;
+    let enc_name =
+        remote::encoded_name(&dir, chunk, &rendition.name, &out_ext);
+    let chunk_name = remote::remote_name(&dir, chunk);
+    let args = rendition.encode_args();
+    let scaled = rendition.scale.is_some();
+    if let Some(target) = &config.target {
+        // Show the first probe of the search, the others only differ in the
+        // CRF
+        let search = quality::CrfSearch::new(target);
+        if let Some(crf) = search.next_crf() {
+            writeln!(
+                plan,
+                "{}Up to {} probes for the highest CRF in {}-{} reaching {} \
+                 {}, starting with:",
+                indent,
+                target.probes,
+                target.crf_range.0,
+                target.crf_range.1,
+                target.metric,
+                target.score
+            )
+            .ok();
+            let args = quality::with_crf(&args, crf);
+            for command in &[
+                remote::encode_command(host, &dir, chunk, &enc_name, &args),
+                quality::metric_command(
+                    host,
+                    &enc_name,
+                    &chunk_name,
+                    target.metric,
+                    scaled,
+                ),
+            ] {
+                writeln!(plan, "{}    {}", indent, command_line(command)).ok();
+            }
+        }
+        writeln!(
+            plan,
+            "{}Unless the last probe was it, the final encode with the \
+             chosen CRF",
+            indent
+        )
+        .ok();
+    } else {
+        let command =
+            remote::encode_command(host, &dir, chunk, &enc_name, &args);
+        writeln!(plan, "{}{}", indent, command_line(&command)).ok();
+    }
+    for &metric in &config.metrics {
+        let command = quality::metric_command(
+            host,
+            &enc_name,
+            &chunk_name,
+            metric,
+            scaled,
+        );
+        writeln!(plan, "{}{}", indent, command_line(&command)).ok();
+    }
+    for command in &[
+        checksum::remote_hash_command(host, &enc_name),
+        remote::return_command(host, &enc_name, rendition_dir),
+    ] {
+        writeln!(plan, "{}{}", indent, command_line(command)).ok();
+    }
+    if config.verify {
+        // Check the returned chunk against its source, encoding it again if
+        // it doesn't match
+        let encoded = rendition_dir.join(remote::encoded_file_name(
+            chunk,
+            &rendition.name,
+            &out_ext,
+        ));
+        for command in &[
+            verify::probe_command(chunk, false),
+            verify::probe_command(&encoded, true),
+        ] {
+            writeln!(plan, "{}{}", indent, command_line(command)).ok();
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the file name of a path for display.
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Formats a command the way it would be typed into a shell.
+fn command_line(command: &Command) -> String {
+    let mut line = quote(&command.get_program().to_string_lossy());
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&quote(&arg.to_string_lossy()));
+    }
+    line
+}
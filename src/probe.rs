@@ -0,0 +1,365 @@
+//! Functions for finding out what the input is before working on it.
+
+use serde_json::Value;
+use std::{fmt, path::Path, process::Command};
+
+use super::Result;
+
+/// How far into the input to look at keyframes in seconds.
+const KEYFRAME_SCAN: u32 = 60;
+
+/// What `ffprobe` tells us about the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaInfo {
+    /// The duration in seconds.
+    pub duration: f64,
+    /// The names of the container format, e.g. `matroska,webm`.
+    pub format: String,
+    /// The size of the file in bytes, if it's known.
+    pub size: Option<u64>,
+    /// The first video stream, which is the one that's encoded.
+    pub video: VideoStream,
+    /// The audio streams, of which only the first is kept.
+    pub audio: Vec<AudioStream>,
+    /// The kinds of all other streams, e.g. `subtitle`, which are dropped.
+    pub other: Vec<String>,
+}
+
+/// A video stream of the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoStream {
+    /// The name of the codec, e.g. `h264`.
+    pub codec: String,
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The average number of frames per second, if it's known.
+    pub frame_rate: Option<f64>,
+    /// The bitrate in bits per second, if it's known.
+    pub bit_rate: Option<u64>,
+    /// The longest distance between two keyframes at the start of the stream
+    /// in seconds, if there are at least two.
+    pub keyframe_interval: Option<f64>,
+}
+
+/// An audio stream of the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioStream {
+    /// The name of the codec, e.g. `aac`.
+    pub codec: String,
+    /// The number of channels.
+    pub channels: u32,
+}
+
+impl MediaInfo {
+    /// Uses `ffprobe` to find out about the streams of the input.
+    pub fn probe(input: &Path) -> Result<Self> {
+        let output = probe_command(input).output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "Failed probing input: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Self::from_json(&serde_json::from_slice(&output.stdout)?)
+    }
+
+    /// Builds the media info from the JSON output of `ffprobe`.
+    fn from_json(json: &Value) -> Result<Self> {
+        let text =
+            |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let number = |value: &Value| value.as_u64().unwrap_or_default() as u32;
+        let streams = json["streams"].as_array().cloned().unwrap_or_default();
+        let kind = |stream: &Value| text(&stream["codec_type"]);
+
+        let video = streams
+            .iter()
+            .find(|stream| kind(stream) == "video")
+            .ok_or("Input has no video stream")?;
+        let index = &video["index"];
+        let keyframes: Vec<f64> = json["packets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|packet| &packet["stream_index"] == index)
+            .filter(|packet| text(&packet["flags"]).starts_with('K'))
+            .filter_map(|packet| text(&packet["pts_time"]).parse().ok())
+            .collect();
+        let keyframe_interval = keyframes
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .fold(None, |max: Option<f64>, gap| {
+                Some(max.map_or(gap, |max| max.max(gap)))
+            });
+
+        Ok(Self {
+            duration: text(&json["format"]["duration"])
+                .parse()
+                .map_err(|_| "Input has no duration")?,
+            format: text(&json["format"]["format_name"]),
+            size: text(&json["format"]["size"]).parse().ok(),
+            video: VideoStream {
+                codec: text(&video["codec_name"]),
+                width: number(&video["width"]),
+                height: number(&video["height"]),
+                frame_rate: fraction(&text(&video["avg_frame_rate"]))
+                    .or_else(|| fraction(&text(&video["r_frame_rate"]))),
+                bit_rate: text(&video["bit_rate"]).parse().ok(),
+                keyframe_interval,
+            },
+            audio: streams
+                .iter()
+                .filter(|stream| kind(stream) == "audio")
+                .map(|stream| AudioStream {
+                    codec: text(&stream["codec_name"]),
+                    channels: number(&stream["channels"]),
+                })
+                .collect(),
+            other: streams
+                .iter()
+                .map(kind)
+                .filter(|kind| kind != "video" && kind != "audio")
+                .collect(),
+        })
+    }
+
+    /// Returns the approximate number of video frames.
+    pub fn frames(&self) -> Option<f64> {
+        Some(self.duration * self.video.frame_rate?)
+    }
+
+    /// Narrows the info down to `length` seconds of the input, assuming its
+    /// size is spread evenly.
+    pub fn trim(&mut self, length: f64) {
+        if let Some(size) = &mut self.size {
+            *size = (*size as f64 * length / self.duration) as u64;
+        }
+        self.duration = length;
+    }
+
+    /// Returns the bitrate of the video in bits per second.
+    ///
+    /// Not every container knows it, in which case the average over the file
+    /// is all we have. That includes the audio, but it's close enough.
+    pub fn video_bit_rate(&self) -> Option<f64> {
+        self.video.bit_rate.map(|rate| rate as f64).or_else(|| {
+            Some(self.size? as f64 * 8.0 / self.duration)
+                .filter(|rate| rate.is_finite())
+        })
+    }
+
+    /// Returns the bitrate to encode the audio with in bits per second, which
+    /// depends on the number of channels it has.
+    pub fn audio_bit_rate(&self) -> u64 {
+        match self.audio.first() {
+            Some(audio) if audio.channels > 2 => 384_000,
+            _ => 192_000,
+        }
+    }
+
+    /// Checks whether a job can work with the input, returning warnings about
+    /// what might not turn out as expected.
+    ///
+    /// Fails if there's no audio, since the output needs some.
+    pub fn check(&self, seconds: f64) -> Result<Vec<String>> {
+        if self.audio.is_empty() {
+            return Err("Input has no audio stream".into());
+        }
+        let mut warnings = Vec::new();
+        if let Some(interval) = self.video.keyframe_interval {
+            if interval > seconds {
+                warnings.push(format!(
+                    "Keyframes are up to {:.2} s apart, but chunks are {:.2} s \
+                     long. Chunks can only start at keyframes, so they'll \
+                     be longer.",
+                    interval, seconds
+                ));
+            }
+        }
+        if self.audio.len() > 1 {
+            warnings.push(format!(
+                "Only the first of the {} audio streams is kept",
+                self.audio.len()
+            ));
+        }
+        if !self.other.is_empty() {
+            warnings.push(format!(
+                "Streams other than video and audio are dropped: {}",
+                self.other.join(", ")
+            ));
+        }
+
+        Ok(warnings)
+    }
+}
+
+impl fmt::Display for MediaInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let video = &self.video;
+        write!(
+            f,
+            "{:.2} s, {} {}x{}",
+            self.duration, video.codec, video.width, video.height
+        )?;
+        if let Some(frame_rate) = video.frame_rate {
+            write!(f, " at {:.3} fps", frame_rate)?;
+        }
+        if let Some(interval) = video.keyframe_interval {
+            write!(f, " with keyframes up to {:.2} s apart", interval)?;
+        }
+        for audio in &self.audio {
+            write!(f, ", {} with {} channels", audio.codec, audio.channels)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds the `ffprobe` command for finding out about the input, including
+/// the keyframes of its first minute.
+pub fn probe_command(input: &Path) -> Command {
+    let mut command = Command::new("ffprobe");
+    command.args([
+        "-v",
+        "error",
+        "-show_entries",
+        "format=duration,format_name,size:stream=index,codec_type,\
+         codec_name,width,height,avg_frame_rate,r_frame_rate,bit_rate,\
+         channels:\
+         packet=stream_index,pts_time,flags",
+        "-read_intervals",
+        &format!("%+{}", KEYFRAME_SCAN),
+        "-of",
+        "json",
+    ]);
+    command.arg(input);
+    command
+}
+
+/// Parses a fraction like `24000/1001`, which is how `ffprobe` gives frame
+/// rates. Unknown ones are `0/0`.
+fn fraction(fraction: &str) -> Option<f64> {
+    let (numerator, denominator) = fraction.split_once('/')?;
+    let numerator: f64 = numerator.parse().ok()?;
+    let denominator: f64 = denominator.parse().ok()?;
+
+    Some(numerator / denominator)
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn input() -> Value {
+        json!({
+            "packets": [
+                {"stream_index": 0, "pts_time": "0.000", "flags": "K__"},
+                {"stream_index": 0, "pts_time": "0.042", "flags": "___"},
+                {"stream_index": 1, "pts_time": "0.500", "flags": "K__"},
+                {"stream_index": 0, "pts_time": "2.002", "flags": "K__"},
+                {"stream_index": 0, "pts_time": "6.006", "flags": "K__"}
+            ],
+            "streams": [
+                {
+                    "index": 0,
+                    "codec_name": "h264",
+                    "codec_type": "video",
+                    "width": 1920,
+                    "height": 1080,
+                    "r_frame_rate": "24000/1001",
+                    "avg_frame_rate": "24000/1001",
+                    "bit_rate": "5000000"
+                },
+                {
+                    "index": 1,
+                    "codec_name": "aac",
+                    "codec_type": "audio",
+                    "channels": 6
+                },
+                {"index": 2, "codec_name": "ac3", "codec_type": "audio"},
+                {"index": 3, "codec_name": "subrip", "codec_type": "subtitle"}
+            ],
+            "format": {
+                "format_name": "matroska,webm",
+                "duration": "150.500000",
+                "size": "150500000"
+            }
+        })
+    }
+
+    #[test]
+    fn reads_the_streams() {
+        let info = MediaInfo::from_json(&input()).unwrap();
+        assert_eq!(info.duration, 150.5);
+        assert_eq!(info.format, "matroska,webm");
+        assert_eq!(info.size, Some(150_500_000));
+        assert_eq!(info.video.codec, "h264");
+        assert_eq!((info.video.width, info.video.height), (1920, 1080));
+        assert!((info.video.frame_rate.unwrap() - 23.976).abs() < 0.001);
+        assert_eq!(info.video.bit_rate, Some(5_000_000));
+        assert_eq!(
+            info.audio,
+            vec![
+                AudioStream {
+                    codec: "aac".to_string(),
+                    channels: 6
+                },
+                AudioStream {
+                    codec: "ac3".to_string(),
+                    channels: 0
+                }
+            ]
+        );
+        assert_eq!(info.other, vec!["subtitle"]);
+    }
+
+    #[test]
+    fn finds_the_longest_keyframe_interval_of_the_video() {
+        let info = MediaInfo::from_json(&input()).unwrap();
+        let interval = info.video.keyframe_interval.unwrap();
+        assert!((interval - 4.004).abs() < 1e-9);
+    }
+
+    #[test]
+    fn falls_back_to_the_nominal_frame_rate() {
+        let mut json = input();
+        json["streams"][0]["avg_frame_rate"] = json!("0/0");
+        json["streams"][0]["r_frame_rate"] = json!("25/1");
+        let info = MediaInfo::from_json(&json).unwrap();
+        assert_eq!(info.video.frame_rate, Some(25.0));
+
+        json["streams"][0]["r_frame_rate"] = json!("0/0");
+        let info = MediaInfo::from_json(&json).unwrap();
+        assert_eq!(info.video.frame_rate, None);
+    }
+
+    #[test]
+    fn leaves_out_what_is_unknown() {
+        let mut json = input();
+        json["packets"] = json!([]);
+        json["streams"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("bit_rate");
+        json["format"].as_object_mut().unwrap().remove("size");
+        let info = MediaInfo::from_json(&json).unwrap();
+        assert_eq!(info.video.keyframe_interval, None);
+        assert_eq!(info.video.bit_rate, None);
+        assert_eq!(info.size, None);
+    }
+
+    #[test]
+    fn rejects_inputs_without_video_or_duration() {
+        let mut json = input();
+        json["format"]["duration"] = json!("N/A");
+        assert!(MediaInfo::from_json(&json).is_err());
+
+        let mut json = input();
+        json["streams"][0]["codec_type"] = json!("data");
+        assert!(MediaInfo::from_json(&json).is_err());
+    }
+}
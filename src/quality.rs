@@ -0,0 +1,395 @@
+//! Functions for measuring the quality of encoded chunks against their
+//! source.
+
+use log::{kv::Value, Level};
+use std::{fmt, process::Command, str::FromStr};
+
+use super::{
+    remote::{self, Encoded},
+    Host,
+};
+
+/// A metric comparing an encoded chunk to its source chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// The structural similarity index.
+    Ssim,
+    /// The peak signal-to-noise ratio.
+    Psnr,
+    /// Netflix's Video Multi-Method Assessment Fusion, if `ffmpeg` on the
+    /// host was built with `libvmaf`.
+    Vmaf,
+}
+
+impl Metric {
+    /// Returns the name of the `ffmpeg` filter computing the metric.
+    fn filter(self) -> &'static str {
+        match self {
+            Metric::Ssim => "ssim",
+            Metric::Psnr => "psnr",
+            Metric::Vmaf => "libvmaf",
+        }
+    }
+
+    /// Returns the key used for the metric in structured log events.
+    pub fn key(self) -> &'static str {
+        match self {
+            Metric::Ssim => "ssim",
+            Metric::Psnr => "psnr",
+            Metric::Vmaf => "vmaf",
+        }
+    }
+
+    /// Extracts the overall score from the log output of the filter.
+    fn parse(self, log: &str) -> Option<f64> {
+        // The filters report their summary on a single line, with the
+        // overall score following a marker
+        let marker = match self {
+            Metric::Ssim => "All:",
+            Metric::Psnr => "average:",
+            Metric::Vmaf => "VMAF score:",
+        };
+        log.lines()
+            .rev()
+            .find_map(|line| line.split(marker).nth(1))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|score| score.parse().ok())
+    }
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ssim" => Ok(Metric::Ssim),
+            "psnr" => Ok(Metric::Psnr),
+            "vmaf" => Ok(Metric::Vmaf),
+            _ => Err(format!("Unknown quality metric {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.key().to_uppercase())
+    }
+}
+
+/// The scores of an encoded chunk for the metrics that could be computed.
+pub type Scores = Vec<(Metric, f64)>;
+
+/// The lowest CRF searched for by default.
+pub const DEFAULT_CRF_MIN: u32 = 18;
+/// The highest CRF searched for by default.
+pub const DEFAULT_CRF_MAX: u32 = 38;
+/// The number of probe encodes per chunk by default.
+pub const DEFAULT_PROBES: u32 = 4;
+
+/// A quality every chunk should reach, with the CRF picked per chunk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Target {
+    /// The metric the quality is measured with.
+    pub metric: Metric,
+    /// The score every chunk should at least reach.
+    pub score: f64,
+    /// The lowest and highest CRF to consider.
+    pub crf_range: (u32, u32),
+    /// The maximum number of probe encodes per chunk.
+    pub probes: u32,
+}
+
+impl Target {
+    /// Creates a target with the default CRF range and number of probes.
+    pub fn new(metric: Metric, score: f64) -> Self {
+        Self {
+            metric,
+            score,
+            crf_range: (DEFAULT_CRF_MIN, DEFAULT_CRF_MAX),
+            probes: DEFAULT_PROBES,
+        }
+    }
+}
+
+/// A binary search for the highest CRF (so the smallest file) that still
+/// reaches the target quality for a chunk.
+///
+/// Quality decreases as the CRF increases, so every probe that reaches the
+/// target rules out all lower CRFs and every one that doesn't all higher
+/// ones.
+#[derive(Debug)]
+pub struct CrfSearch {
+    target: f64,
+    low: u32,
+    high: u32,
+    min: u32,
+    probes_left: u32,
+    probed: Vec<(u32, f64)>,
+}
+
+impl CrfSearch {
+    /// Starts a new search.
+    pub fn new(target: &Target) -> Self {
+        Self {
+            target: target.score,
+            low: target.crf_range.0,
+            high: target.crf_range.1,
+            min: target.crf_range.0,
+            probes_left: target.probes,
+            probed: Vec::new(),
+        }
+    }
+
+    /// Returns the CRF to probe next, or `None` if the search is over.
+    pub fn next_crf(&self) -> Option<u32> {
+        if self.probes_left == 0 || self.low > self.high {
+            None
+        } else {
+            Some(self.low + (self.high - self.low) / 2)
+        }
+    }
+
+    /// Records the score a probe encode reached.
+    pub fn record(&mut self, crf: u32, score: f64) {
+        self.probes_left = self.probes_left.saturating_sub(1);
+        self.probed.push((crf, score));
+        if score >= self.target {
+            self.low = crf + 1;
+        } else if crf == 0 {
+            // Even the best quality isn't enough, so we're done
+            self.low = self.high + 1;
+        } else {
+            self.high = crf - 1;
+        }
+    }
+
+    /// Returns the score a CRF reached, if it was probed.
+    pub fn score(&self, crf: u32) -> Option<f64> {
+        self.probed
+            .iter()
+            .find(|(probed, _)| *probed == crf)
+            .map(|(_, score)| *score)
+    }
+
+    /// Returns the highest probed CRF reaching the target, or the lowest
+    /// allowed one if none did.
+    pub fn chosen(&self) -> u32 {
+        self.probed
+            .iter()
+            .filter(|(_, score)| *score >= self.target)
+            .map(|(crf, _)| *crf)
+            .max()
+            .unwrap_or(self.min)
+    }
+}
+
+/// Returns the `ffmpeg` arguments with the CRF set to the given value.
+pub fn with_crf(args: &[String], crf: u32) -> Vec<String> {
+    let mut args = args.to_vec();
+    match args.iter().position(|arg| arg == "-crf") {
+        Some(i) if i + 1 < args.len() => args[i + 1] = crf.to_string(),
+        _ => args.extend(["-crf".to_string(), crf.to_string()]),
+    }
+    args
+}
+
+/// Builds the `ssh` command computing a metric on a host.
+///
+/// The encoded chunk is the main input and the source chunk the reference,
+/// which is the order `libvmaf` expects. If the chunk was `scaled`, it's
+/// scaled back to the size of the reference first, since the filters can
+/// only compare frames of the same size.
+pub fn metric_command(
+    host: &Host,
+    enc_name: &str,
+    chunk_name: &str,
+    metric: Metric,
+    scaled: bool,
+) -> Command {
+    let filter = if scaled {
+        format!(
+            "[0:v][1:v]scale2ref=flags=bicubic[enc][ref];[enc][ref]{}",
+            metric.filter()
+        )
+    } else {
+        metric.filter().to_string()
+    };
+    remote::ssh_command(
+        host,
+        [
+            "ffmpeg",
+            "-hide_banner",
+            "-i",
+            enc_name,
+            "-i",
+            chunk_name,
+            "-lavfi",
+            &filter,
+            "-f",
+            "null",
+            "-",
+        ],
+    )
+}
+
+/// Builds the `ssh` command listing the filters `ffmpeg` on a host supports.
+pub fn filters_command(host: &Host) -> Command {
+    remote::ssh_command(host, ["ffmpeg", "-hide_banner", "-filters"])
+}
+
+/// Checks whether `ffmpeg` on a host can compute VMAF, given the output of
+/// [`filters_command`].
+pub fn supports_vmaf(filters: &str) -> bool {
+    filters
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some("libvmaf"))
+}
+
+/// Computes a metric on a host, returning `None` if it failed.
+pub fn measure(
+    host: &Host,
+    enc_name: &str,
+    chunk_name: &str,
+    metric: Metric,
+    scaled: bool,
+) -> Option<f64> {
+    let output = metric_command(host, enc_name, chunk_name, metric, scaled)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // ffmpeg writes the filter summaries to stderr
+    metric.parse(&String::from_utf8_lossy(&output.stderr))
+}
+
+/// Logs the scores of every chunk of a rendition and their means over the
+/// whole file, referring to the rendition with `label`.
+pub fn report(chunks: &[Encoded], label: &str) {
+    for Encoded {
+        chunk,
+        rendition,
+        host,
+        scores,
+        crf,
+        ..
+    } in chunks
+    {
+        let mut fields = vec![
+            ("stage", Value::from("report")),
+            ("chunk", Value::from(chunk.as_str())),
+            ("rendition", Value::from(rendition.as_str())),
+            ("host", Value::from(host.as_str())),
+        ];
+        fields.extend(crf.map(|crf| ("crf", Value::from(crf))));
+        fields.extend(scores.iter().map(|(m, s)| (m.key(), Value::from(*s))));
+        let crf = crf
+            .map(|crf| format!(" with CRF {}", crf))
+            .unwrap_or_default();
+        event!(
+            @source Level::Info,
+            &fields;
+            "Quality of {}{} encoded by {}{}: {}",
+            chunk,
+            label,
+            host,
+            crf,
+            format_scores(scores)
+        );
+    }
+
+    // Average every metric over the chunks it was computed for, weighted by
+    // their length so a short chunk at a zone boundary counts for less
+    let mut means: Scores = Vec::new();
+    for metric in [Metric::Ssim, Metric::Psnr, Metric::Vmaf] {
+        let (sum, duration) = chunks
+            .iter()
+            .flat_map(|encoded| {
+                encoded
+                    .scores
+                    .iter()
+                    .filter(|(m, _)| *m == metric)
+                    .map(move |(_, s)| (*s, encoded.task.span.duration))
+            })
+            .fold((0.0, 0.0), |(sum, total), (score, duration)| {
+                (sum + score * duration, total + duration)
+            });
+        if duration > 0.0 {
+            means.push((metric, sum / duration));
+        }
+    }
+    if means.is_empty() {
+        return;
+    }
+    let mut fields = vec![("stage", Value::from("report"))];
+    fields.extend(
+        chunks
+            .first()
+            .map(|chunk| ("rendition", Value::from(chunk.rendition.as_str()))),
+    );
+    fields.extend(means.iter().map(|(m, s)| (m.key(), Value::from(*s))));
+    event!(
+        @source Level::Info,
+        &fields;
+        "Mean quality over all chunks{}: {}",
+        label,
+        format_scores(&means)
+    );
+}
+
+/// Formats scores for humans.
+fn format_scores(scores: &[(Metric, f64)]) -> String {
+    if scores.is_empty() {
+        return "not available".to_string();
+    }
+    scores
+        .iter()
+        .map(|(metric, score)| format!("{} {:.4}", metric, score))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(crf_range: (u32, u32), probes: u32) -> Target {
+        Target {
+            crf_range,
+            probes,
+            ..Target::new(Metric::Vmaf, 90.0)
+        }
+    }
+
+    #[test]
+    fn search_finds_highest_crf_reaching_target() {
+        let mut search = CrfSearch::new(&target((18, 38), 4));
+        for (crf, score) in [(28, 92.0), (33, 88.0), (30, 91.0), (31, 89.5)] {
+            assert_eq!(search.next_crf(), Some(crf));
+            search.record(crf, score);
+        }
+        assert_eq!(search.next_crf(), None);
+        assert_eq!(search.chosen(), 30);
+        assert_eq!(search.score(33), Some(88.0));
+        assert_eq!(search.score(29), None);
+    }
+
+    #[test]
+    fn search_stops_when_range_is_exhausted() {
+        let mut search = CrfSearch::new(&target((18, 38), 10));
+        for crf in [28, 22, 19, 18] {
+            assert_eq!(search.next_crf(), Some(crf));
+            search.record(crf, 80.0);
+        }
+        assert_eq!(search.next_crf(), None);
+        assert_eq!(search.chosen(), 18);
+    }
+
+    #[test]
+    fn search_stops_below_crf_zero() {
+        let mut search = CrfSearch::new(&target((0, 0), 4));
+        search.record(0, 80.0);
+        assert_eq!(search.next_crf(), None);
+        assert_eq!(search.chosen(), 0);
+    }
+}
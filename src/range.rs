@@ -0,0 +1,184 @@
+//! The part of the input a job encodes.
+
+use serde_json::Value;
+use std::{fmt, path::Path, process::Command};
+
+use super::{MediaInfo, Result};
+
+/// A time range of the input in seconds, which starts at a keyframe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    /// Where the range starts.
+    pub start: f64,
+    /// Where the range ends.
+    pub end: f64,
+}
+
+impl Range {
+    /// Finds the range of the input to encode from the `start` and `end`
+    /// that were asked for, or `None` if it's all of it. `info` is narrowed
+    /// down to the range.
+    ///
+    /// Chunks are copied from the input, so they can only start at a
+    /// keyframe. The start is moved back to the one before it, which the
+    /// audio then starts at as well to stay in sync.
+    pub fn find(
+        input: &Path,
+        info: &mut MediaInfo,
+        start: Option<f64>,
+        end: Option<f64>,
+    ) -> Result<Option<Self>> {
+        if start.is_none() && end.is_none() {
+            return Ok(None);
+        }
+        let start = start.unwrap_or_default();
+        let end = end.unwrap_or(info.duration).min(info.duration);
+        if start >= info.duration {
+            return Err(format!(
+                "Start at {:.2} s is past the end of the input at {:.2} s",
+                start, info.duration
+            )
+            .into());
+        }
+        if end <= start {
+            return Err(format!(
+                "End at {:.2} s needs to be after the start at {:.2} s",
+                end, start
+            )
+            .into());
+        }
+        let start = if start > 0.0 {
+            keyframe_before(input, start)?
+        } else {
+            start
+        };
+
+        let range = Self { start, end };
+        info.trim(range.length());
+        Ok(Some(range))
+    }
+
+    /// Returns the length in seconds.
+    pub fn length(self) -> f64 {
+        self.end - self.start
+    }
+
+    /// Returns the `ffmpeg` input options that read only the range.
+    pub fn input_args(self) -> Vec<String> {
+        vec![
+            "-ss".to_string(),
+            self.start.to_string(),
+            "-to".to_string(),
+            self.end.to_string(),
+        ]
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.2} s to {:.2} s", self.start, self.end)
+    }
+}
+
+/// Parses a point in time, which is either a number of seconds or of the form
+/// `[HH:]MM:SS[.fraction]`.
+pub fn parse_time(time: &str) -> std::result::Result<f64, String> {
+    let invalid = || format!("Invalid time {}", time);
+    let parts = time
+        .split(':')
+        .map(|part| part.parse::<f64>().map_err(|_| invalid()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    if parts.len() > 3 || parts.iter().any(|&part| part < 0.0) {
+        return Err(invalid());
+    }
+    let seconds = parts.iter().fold(0.0, |total, part| total * 60.0 + part);
+    if !seconds.is_finite() {
+        return Err(invalid());
+    }
+
+    Ok(seconds)
+}
+
+/// Uses `ffprobe` to find the time of the last video keyframe at or before
+/// `time`, which is where seeking there ends up.
+fn keyframe_before(input: &Path, time: f64) -> Result<f64> {
+    let output = keyframe_command(input, time).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed finding keyframe: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    let json: Value = serde_json::from_slice(&output.stdout)?;
+
+    let keyframe = json["packets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|packet| {
+            packet["flags"]
+                .as_str()
+                .unwrap_or_default()
+                .starts_with('K')
+        })
+        .find_map(|packet| packet["pts_time"].as_str()?.parse().ok())
+        .ok_or_else(|| format!("Found no keyframe before {:.2} s", time))?;
+
+    Ok(keyframe)
+}
+
+/// Builds the `ffprobe` command for finding the keyframe that seeking to
+/// `time` ends up at, which is the first packet read after it.
+fn keyframe_command(input: &Path, time: f64) -> Command {
+    let mut command = Command::new("ffprobe");
+    command.args([
+        "-v",
+        "error",
+        "-select_streams",
+        "v:0",
+        "-show_entries",
+        "packet=pts_time,flags",
+        "-read_intervals",
+        &format!("{}%+#1", time),
+        "-of",
+        "json",
+    ]);
+    command.arg(input);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds() {
+        assert_eq!(parse_time("90"), Ok(90.0));
+        assert_eq!(parse_time("1.5"), Ok(1.5));
+    }
+
+    #[test]
+    fn parses_minutes_and_hours() {
+        assert_eq!(parse_time("1:30"), Ok(90.0));
+        assert_eq!(parse_time("1:02:03.5"), Ok(3723.5));
+    }
+
+    #[test]
+    fn rejects_invalid_times() {
+        for time in ["", "abc", "1:2:3:4", "-5", "1:-3", "inf", "nan", "1::2"]
+        {
+            assert!(parse_time(time).is_err(), "{} was accepted", time);
+        }
+    }
+
+    #[test]
+    fn reads_only_the_range() {
+        let range = Range {
+            start: 10.01,
+            end: 70.5,
+        };
+        assert!((range.length() - 60.49).abs() < 1e-9);
+        assert_eq!(range.input_args(), ["-ss", "10.01", "-to", "70.5"]);
+    }
+}
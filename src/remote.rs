@@ -1,128 +1,466 @@
 //! Functions for operations on remote hosts.
 
-use crossbeam::channel::{self, Receiver};
-use log::{debug, info};
+use crossbeam::channel::{self, Receiver, Select, SendError, Sender};
+use log::{debug, Level};
 use std::{
-    path::PathBuf,
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
     process::Command,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     sync::Arc,
     thread,
+    time::{Duration, Instant},
 };
 
-/// The name of the temporary directory in the home directory of remote hosts.
+use super::{
+    budget, checksum,
+    quality::{self, CrfSearch, Metric, Scores, Target},
+    shell,
+    template::Span,
+    verify, Config, Host, POLL_INTERVAL,
+};
+
+/// The prefix of the temporary directory in the home directory of remote
+/// hosts, which is followed by the job ID.
 pub static TMP_DIR: &str = "shepherd_tmp_remote";
+/// How often a file is transferred before giving up on getting it across
+/// intact.
+const TRANSFER_ATTEMPTS: u32 = 3;
+/// How often a chunk is encoded before giving up on getting a valid result.
+const ENCODE_ATTEMPTS: u32 = 3;
+
+/// A chunk to encode for a rendition.
+#[derive(Clone, Debug)]
+pub struct Task {
+    /// The path to the local chunk.
+    pub chunk: PathBuf,
+    /// Where the chunk is in the input.
+    pub span: Span,
+    /// The index of the rendition in the job's configuration.
+    pub rendition: usize,
+    /// How often the chunk has been encoded without a valid result.
+    pub failures: u32,
+    /// Why the last encode was rejected, if it was.
+    pub problem: Option<String>,
+    /// Which pass of the encode this is.
+    pub pass: Pass,
+}
+
+impl Task {
+    /// Creates a task that hasn't been attempted yet and encodes the chunk
+    /// in a single pass.
+    pub fn new(chunk: PathBuf, span: Span, rendition: usize) -> Self {
+        Self {
+            chunk,
+            span,
+            rendition,
+            failures: 0,
+            problem: None,
+            pass: Pass::Single,
+        }
+    }
+}
+
+/// The pass of an encode a task is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Pass {
+    /// Encodes the chunk with the given options.
+    Single,
+    /// Analyses the chunk with a first pass at a bitrate, which only writes
+    /// statistics.
+    First(u64),
+    /// Encodes the chunk at a bitrate, after a first pass.
+    Second {
+        /// The bitrate in bits per second.
+        bit_rate: u64,
+        /// The visit of the host that did the first pass, whose statistics
+        /// are only there. A host that was drained removed them on leaving,
+        /// so once it's added again, that's another visit.
+        analysed_by: usize,
+    },
+}
+
+/// What the first pass over a chunk found out.
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    /// The task of the first pass.
+    pub task: Task,
+    /// The visit of the host that did it, which counts every time a host
+    /// joins the job.
+    pub visit: usize,
+    /// How complex the chunk is, if the statistics could be read.
+    pub complexity: Option<f64>,
+}
+
+/// The tasks of a job, shared by all hosts.
+///
+/// Tasks can be put back until every one is done, so hosts keep waiting for
+/// more as long as any are still being worked on.
+#[derive(Clone)]
+pub struct Queue {
+    sender: Sender<Task>,
+    receiver: Receiver<Task>,
+    /// The number of tasks that aren't done, including those being worked on
+    /// and those that are expected.
+    pending: Arc<AtomicUsize>,
+    /// Hands the analyses of first passes to whoever plans the second.
+    analysed: Sender<Analysis>,
+    analyses: Receiver<Analysis>,
+    /// Tells whoever waits for the tasks why one can't be done.
+    failed: Sender<String>,
+    failures: Receiver<String>,
+}
+
+impl Queue {
+    /// Creates a queue holding the given tasks.
+    pub fn new(tasks: Vec<Task>) -> Self {
+        let (sender, receiver) = channel::unbounded();
+        let (analysed, analyses) = channel::unbounded();
+        let (failed, failures) = channel::unbounded();
+        let pending = Arc::new(AtomicUsize::new(tasks.len()));
+        for task in tasks {
+            // We hold the receiver, so sending can't fail
+            sender.send(task).expect("Failed queueing task");
+        }
+
+        Self {
+            sender,
+            receiver,
+            pending,
+            analysed,
+            analyses,
+            failed,
+            failures,
+        }
+    }
+
+    /// Counts `count` tasks that are only added later, so hosts wait for
+    /// them.
+    pub fn expect(&self, count: usize) {
+        self.pending.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Adds a task that was expected.
+    pub fn add(&self, task: Task) {
+        self.put_back(task);
+    }
+
+    /// Returns the analyses of first passes that arrived since the last
+    /// call.
+    pub fn analyses(&self) -> Vec<Analysis> {
+        self.analyses.try_iter().collect()
+    }
+
+    /// Returns why a task can't be done, if one can't, which fails the job.
+    pub fn failure(&self) -> Option<String> {
+        self.failures.try_recv().ok()
+    }
+
+    /// Returns the number of tasks that aren't done yet.
+    pub fn remaining(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Takes the next task, waiting for a while if there is none, since
+    /// tasks that are still being worked on may be put back.
+    fn take(&self) -> Option<Task> {
+        self.receiver.recv_timeout(POLL_INTERVAL).ok()
+    }
+
+    /// Puts a task back for another attempt.
+    fn put_back(&self, task: Task) {
+        // We hold the receiver, so sending can't fail
+        self.sender.send(task).expect("Failed queueing task");
+    }
+
+    /// Marks a task as done.
+    fn done(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Gives up on a task, which fails the job.
+    fn fail(&self, reason: String) {
+        // We hold the receiver, so sending can't fail
+        self.failed.send(reason).expect("Failed passing on failure");
+    }
+
+    /// Marks the task of a first pass as done with its analysis.
+    fn analysed(&self, analysis: Analysis) {
+        // We hold the receiver, so sending can't fail
+        self.analysed
+            .send(analysis)
+            .expect("Failed passing on analysis");
+        self.done();
+    }
+}
+
+/// A chunk that has been encoded on a host.
+#[derive(Clone, Debug)]
+pub struct Encoded {
+    /// The task it was encoded for.
+    pub task: Task,
+    /// The file name of the source chunk.
+    pub chunk: String,
+    /// The name of the rendition it was encoded for.
+    pub rendition: String,
+    /// The host that encoded it.
+    pub host: String,
+    /// The remote file name of the encoded chunk.
+    pub name: String,
+    /// The quality of the encoded chunk compared to its source.
+    pub scores: Scores,
+    /// The CRF picked to reach the target quality, if there was one.
+    pub crf: Option<u32>,
+    /// How long the encoder spent on it, including probes and metrics.
+    pub elapsed: Duration,
+}
 
 /// The parent thread managing the operations for a host.
+///
+/// Once `draining` is set, it stops taking chunks from the queue, and when
+/// it's done with the ones it has, it removes its temporary directory and
+/// closes its connection, so the host can be added again later.
+/// Returns the chunks it encoded once they have been transferred back.
 pub fn host_thread(
-    host: String,
-    global_receiver: Receiver<PathBuf>,
+    host: Host,
+    queue: Queue,
     encoded_dir: PathBuf,
-    out_ext: String,
-    args: Arc<Vec<String>>,
+    config: Arc<Config>,
+    draining: Arc<AtomicBool>,
     running: Arc<AtomicBool>,
-) {
-    debug!("Spawned host thread {}", host);
-
-    // Clean up temporary directory on host. This is necessary, because it's
-    // possible that the user ran with the --keep flag before. While our
-    // application wouldn't get confused by old chunks lying around (they're
-    // overwritten and those that aren't are disregarded, because it keeps
-    // track of its chunks), we don't want the user to get the wrong idea.
-    // Also, we don't care if this fails, because if it did then the directory
-    // didn't exist anyway.
-    Command::new("ssh")
-        .args(&[&host, "rm", "-r", crate::remote::TMP_DIR])
-        .output()
-        .expect("Failed executing ssh command");
+) -> Vec<Encoded> {
+    // Every time a host joins the job is a visit of its own
+    static VISITS: AtomicUsize = AtomicUsize::new(0);
+    let visit = VISITS.fetch_add(1, Ordering::SeqCst);
+    let slots = host.slots;
+    debug!("Spawned host thread {} for visit {}", host, visit);
+    let dir = tmp_dir(&config.job);
 
-    // Create temporary directory on host
-    let output = Command::new("ssh")
-        .args(&[&host, "mkdir", TMP_DIR])
+    // Remove what crashed jobs left behind. It doesn't matter if they never
+    // got to this host.
+    if !config.crashed.is_empty() {
+        let dirs: Vec<_> = config.crashed.iter().map(|j| tmp_dir(j)).collect();
+        purge_command(&host, &dirs).output().ok();
+    }
+    // Create temporary directory on host. Since it's named after the job, we
+    // don't have to clean up after previous runs, and it fails if the
+    // directory already exists, so we never touch another job's files.
+    let output = mkdir_command(&host, &dir)
         .output()
         .expect("Failed executing ssh command");
-    assert!(
-        output.status.success() || !running.load(Ordering::SeqCst),
-        "Failed creating remote temporary directory"
-    );
+    if !output.status.success() {
+        // Without it, the host can't do anything, but the others can
+        if running.load(Ordering::SeqCst) {
+            event!(
+                Level::Error,
+                stage = "encode",
+                host = host,
+                error = String::from_utf8_lossy(&output.stderr).trim();
+                "Failed creating remote temporary directory on {}, so it \
+                 doesn't take part",
+                host
+            );
+        }
+        return Vec::new();
+    }
+
+    // Not every ffmpeg build comes with libvmaf, so check whether this one
+    // does before we ask it for VMAF scores
+    let mut config = Config::clone(&config);
+    let metrics = &mut config.metrics;
+    let target = &mut config.target;
+    let wants_vmaf = metrics.contains(&Metric::Vmaf)
+        || target.map(|t| t.metric) == Some(Metric::Vmaf);
+    if wants_vmaf {
+        let output = quality::filters_command(&host)
+            .output()
+            .expect("Failed executing ssh command");
+        if !quality::supports_vmaf(&String::from_utf8_lossy(&output.stdout)) {
+            event!(
+                Level::Warn,
+                stage = "encode",
+                host = host;
+                "ffmpeg on {} doesn't support libvmaf, skipping VMAF",
+                host
+            );
+            metrics.retain(|&m| m != Metric::Vmaf);
+            if target.map(|t| t.metric) == Some(Metric::Vmaf) {
+                event!(
+                    Level::Warn,
+                    stage = "encode",
+                    host = host;
+                    "{} can't search for the target VMAF, using the given \
+                     options instead",
+                    host
+                );
+                *target = None;
+            }
+        }
+    }
+    // The score of the target metric always goes into the report
+    if let Some(Target { metric, .. }) = *target {
+        if !metrics.contains(&metric) {
+            metrics.push(metric);
+        }
+    }
+
+    let config = Arc::new(config);
 
-    // Create a channel holding a single chunk at a time for the encoder thread
-    let (sender, receiver) = channel::bounded(0);
-    // Create copy of host for thread
+    // Create a channel for the encoders to hand finished chunks to the
+    // returner thread
+    let (done_sender, done_receiver) = channel::unbounded();
+    // Start an encoder thread for every slot, each with a channel holding a
+    // single chunk at a time
+    let mut senders = Vec::with_capacity(slots);
+    let mut encoders = Vec::with_capacity(slots);
+    for slot in 0..slots {
+        let (sender, receiver) = channel::bounded(0);
+        senders.push(sender);
+        // Create copies for the thread
+        let host_cpy = host.clone();
+        let done = done_sender.clone();
+        let q = queue.clone();
+        // Increase reference counts for Arcs
+        let r = Arc::clone(&running);
+        let c = Arc::clone(&config);
+        let name = if slots > 1 {
+            format!("{}-encoder-{}", host, slot)
+        } else {
+            format!("{}-encoder", host)
+        };
+        let handle = thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                encoder_thread(host_cpy, visit, c, receiver, done, q, r)
+            })
+            .expect("Failed spawning thread");
+        encoders.push(handle);
+    }
+    // Only the encoders may keep the returner's channel connected
+    drop(done_sender);
+    // Start the returner thread
     let host_cpy = host.clone();
-    // Increase reference counts for Arcs
+    let q = queue.clone();
+    let verify = config.verify;
     let r = Arc::clone(&running);
-    let a = Arc::clone(&args);
-    // Start the encoder thread
-    let handle = thread::Builder::new()
-        .name(format!("{}-encoder", host))
-        .spawn(move || encoder_thread(host_cpy, out_ext, a, receiver, r))
+    let returner = thread::Builder::new()
+        .name(format!("{}-returner", host))
+        .spawn(move || {
+            returner_thread(host_cpy, encoded_dir, done_receiver, q, verify, r)
+        })
         .expect("Failed spawning thread");
 
-    // Try to fetch a chunk from the global channel
-    while let Ok(chunk) = global_receiver.recv() {
-        debug!("Host thread {} received chunk {:?}", host, chunk);
+    // Remember the chunks we already transferred, since we may encode the
+    // same chunk for several renditions
+    let mut transferred = HashSet::new();
+    // Try to fetch a chunk from the queue until every one is done, unless
+    // we've been asked to leave
+    while queue.remaining() > 0
+        && !draining.load(Ordering::SeqCst)
+        && running.load(Ordering::SeqCst)
+    {
+        // Without the returner, the chunks we take would never be done
+        if returner.is_finished() {
+            break;
+        }
+        let task = match queue.take() {
+            Some(task) => task,
+            // Wait for chunks to be put back
+            None => continue,
+        };
+        debug!("Host thread {} received {:?}", host, task);
         // Transfer chunk to host
-        let output = Command::new("scp")
-            .args(&[
-                chunk.to_str().expect("Invalid Unicode"),
-                &format!("{}:{}", host, TMP_DIR),
-            ])
-            .output()
-            .expect("Failed executing scp command");
-        assert!(
-            output.status.success() || !running.load(Ordering::SeqCst),
-            "Failed transferring chunk"
-        );
+        if !transferred.contains(&task.chunk) {
+            transfer_chunk(&host, &dir, &task.chunk, &running);
+            transferred.insert(task.chunk.clone());
+        }
 
-        // Pass the chunk to the encoder thread (blocks until encoder is ready
-        // to receive and fails if it terminated prematurely)
-        if sender.send(chunk).is_err() {
-            // Encoder stopped, so quit early
+        // Pass the chunk to the first encoder thread that's ready to receive
+        // it (blocks until one is)
+        if !hand_over(&mut senders, task) {
+            // All encoders stopped, so quit early
             break;
         }
     }
-    // Since the global channel is empty, drop our sender to disconnect the
-    // local channel
-    drop(sender);
-    debug!("Host thread {} waiting for encoder to finish", host);
+    if draining.load(Ordering::SeqCst) {
+        event!(
+            Level::Info,
+            stage = "control",
+            host = host;
+            "{} is draining and leaves once it's done with its chunks",
+            host
+        );
+    }
+    // Since the queue is done or we're draining, drop our senders to
+    // disconnect the local channels
+    drop(senders);
+    debug!("Host thread {} waiting for encoders to finish", host);
 
-    // Wait for the encoder
-    let encoded = handle.join().expect("Encoder thread panicked");
+    // Wait for the encoders, which disconnect the returner's channel when
+    // they're done, and then for the returner to get the last chunks back
+    for handle in encoders {
+        handle.join().expect("Encoder thread panicked");
+    }
+    let encoded = returner.join().expect("Returner thread panicked");
+    // The job goes on without a drained host, so don't leave anything
+    // behind on it
+    if draining.load(Ordering::SeqCst) {
+        if !config.keep {
+            cleanup_command(&host, &dir).output().ok();
+        }
+        close_command(&host).output().ok();
+    }
     // Abort early if signal was sent
     if !running.load(Ordering::SeqCst) {
-        info!("{} exiting", host);
-        return;
+        event!(Level::Info, stage = "encode", host = host; "{} exiting", host);
+        return Vec::new();
     }
     debug!("Host thread {} got encoded chunks {:?}", host, encoded);
+    debug!("Host thread {} exiting", host);
 
-    // Get a &str from encoded_dir PathBuf
-    let encoded_dir = encoded_dir.to_str().expect("Invalid Unicode");
-    // Transfer the encoded chunks back
-    for chunk in &encoded {
-        let output = Command::new("scp")
-            .args(&[&format!("{}:{}", host, chunk), encoded_dir])
-            .output()
-            .expect("Failed executing scp command");
-        assert!(
-            output.status.success() || !running.load(Ordering::SeqCst),
-            "Failed transferring encoded chunk"
-        );
-        info!("{} returned encoded chunk {}", host, chunk);
+    encoded
+}
+
+/// Sends a chunk to whichever encoder is ready for it first.
+///
+/// Encoders that terminated prematurely are removed, and `false` is returned
+/// if none are left.
+fn hand_over(senders: &mut Vec<Sender<Task>>, mut task: Task) -> bool {
+    while !senders.is_empty() {
+        let mut select = Select::new();
+        for sender in senders.iter() {
+            select.send(sender);
+        }
+        let operation = select.select();
+        let index = operation.index();
+        match operation.send(&senders[index], task) {
+            Ok(()) => return true,
+            Err(SendError(returned)) => {
+                // This encoder stopped, so try the others
+                senders.remove(index);
+                task = returned;
+            }
+        }
     }
 
-    debug!("Host thread {} exiting", host);
+    false
 }
 
-/// Encodes chunks on a host and returns the encoded remote file names.
-fn encoder_thread(
-    host: String,
-    out_ext: String,
-    args: Arc<Vec<String>>,
-    receiver: Receiver<PathBuf>,
+/// Transfers encoded chunks back as soon as an encoder is done with them
+/// and returns them once all encoders have quit.
+///
+/// If we `verify`, chunks that don't match their source are put back into
+/// the queue to be encoded again.
+fn returner_thread(
+    host: Host,
+    encoded_dir: PathBuf,
+    receiver: Receiver<Encoded>,
+    queue: Queue,
+    verify: bool,
     running: Arc<AtomicBool>,
-) -> Vec<String> {
-    // We'll use this to store the encoded chunks' remote file names.
+) -> Vec<Encoded> {
+    // We'll use this to store the returned chunks.
     let mut encoded = Vec::new();
 
     while let Ok(chunk) = receiver.recv() {
@@ -131,50 +469,603 @@ fn encoder_thread(
             break;
         }
 
-        debug!("Encoder thread {} received chunk {:?}", host, chunk);
-        // Construct the chunk's remote file name
-        let chunk_name = format!(
-            "{}/{}",
-            TMP_DIR,
-            chunk
-                .file_name()
-                .expect("No normal file")
-                .to_str()
-                .expect("Invalid Unicode")
-        );
-        // Construct the encoded chunk's remote file name
-        let enc_name = format!(
-            "{}/enc_{}.{}",
-            TMP_DIR,
-            chunk
-                .file_stem()
-                .expect("No normal file")
-                .to_str()
-                .expect("Invalid Unicode"),
-            out_ext
+        let rendition_dir = encoded_dir.join(&chunk.rendition);
+        return_chunk(&host, &chunk.name, &rendition_dir, &running);
+        let file_name =
+            Path::new(&chunk.name).file_name().expect("No normal file");
+        event!(
+            Level::Info,
+            stage = "return",
+            host = host,
+            chunk = file_name.to_string_lossy(),
+            rendition = chunk.rendition;
+            "{} returned encoded chunk {}",
+            host,
+            chunk.name
         );
 
-        // Build the ffmpeg command
-        let mut command: Vec<&str> =
-            vec![&host, "ffmpeg", "-y", "-i", &chunk_name];
-        command.extend(args.iter().map(|s| s.as_str()));
-        command.push(&enc_name);
+        // Make sure it's complete, since an encoder that crashed halfway
+        // may still have left a file
+        let local_path = rendition_dir.join(file_name);
+        if verify {
+            let checked =
+                verify::check_chunk(&chunk.task.chunk, &local_path, &running);
+            if let Err(problem) = checked {
+                reject(&host, chunk, &local_path, &problem, &queue);
+                continue;
+            }
+        }
+        queue.done();
+        encoded.push(chunk);
+    }
+    debug!("Returner thread {} exiting", host);
+
+    encoded
+}
+
+/// Encodes chunks on a host and passes them on to the returner along with
+/// their scores for the requested metrics.
+///
+/// With a target quality, every chunk is first encoded a few times to find
+/// the CRF reaching it.
+fn encoder_thread(
+    host: Host,
+    visit: usize,
+    config: Arc<Config>,
+    receiver: Receiver<Task>,
+    sender: Sender<Encoded>,
+    queue: Queue,
+    running: Arc<AtomicBool>,
+) {
+    let metrics = &config.metrics;
+    let target = config.target;
+    let dir = tmp_dir(&config.job);
+
+    while let Ok(task) = receiver.recv() {
+        // Abort early if signal was sent
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+
+        debug!("Encoder thread {} received {:?}", host, task);
+        let started = Instant::now();
+        let chunk = task.chunk.clone();
+        let rendition = &config.renditions[task.rendition];
+        let args = &config
+            .chunk_rendition(rendition, task.span, &host.name)
+            .encode_args();
+        let label = config.label(rendition);
+        let scaled = rendition.scale.is_some();
+        // Construct the encoded chunk's remote file name. The extension was
+        // checked before the job started.
+        let out_ext = super::output_extension(&rendition.output)
+            .expect("Invalid output extension");
+        let enc_name = encoded_name(&dir, &chunk, &rendition.name, &out_ext);
+        let chunk_file = chunk.file_name().expect("No normal file");
+        let chunk_file = chunk_file.to_string_lossy();
+        let passlog = budget::passlog(&dir, &chunk, &rendition.name);
+
+        // The first pass only gets statistics for planning the second
+        if let Pass::First(bit_rate) = task.pass {
+            let args = budget::pass_args(args, bit_rate, 1, &passlog);
+            first_pass(&host, &dir, &chunk, &args, &label, &running);
+            let output = budget::stats_command(&host, &passlog)
+                .output()
+                .expect("Failed executing ssh command");
+            let complexity = Some(output)
+                .filter(|output| output.status.success())
+                .and_then(|output| {
+                    budget::complexity(&String::from_utf8_lossy(
+                        &output.stdout,
+                    ))
+                });
+            queue.analysed(Analysis {
+                task,
+                visit,
+                complexity,
+            });
+            continue;
+        }
+
+        let mut search = target.as_ref().map(CrfSearch::new);
+        let crf = match (&task.pass, &mut search, target) {
+            (
+                Pass::Second {
+                    bit_rate,
+                    analysed_by,
+                },
+                _,
+                _,
+            ) => {
+                // The statistics of the first pass stay on its host for as
+                // long as it takes part
+                if *analysed_by != visit {
+                    let args = budget::pass_args(args, *bit_rate, 1, &passlog);
+                    first_pass(&host, &dir, &chunk, &args, &label, &running);
+                }
+                let args = budget::pass_args(args, *bit_rate, 2, &passlog);
+                encode(
+                    &host, &dir, &chunk, &enc_name, &args, &label, &running,
+                );
+                None
+            }
+            (_, Some(search), Some(target)) => {
+                // Probe CRFs until we know which one to use
+                let mut last = None;
+                while let Some(crf) = search.next_crf() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let args = quality::with_crf(args, crf);
+                    encode(
+                        &host, &dir, &chunk, &enc_name, &args, &label,
+                        &running,
+                    );
+                    // A failed measurement counts as missing the target
+                    let score = measure(
+                        &host,
+                        &dir,
+                        &chunk,
+                        &enc_name,
+                        target.metric,
+                        scaled,
+                        &running,
+                    )
+                    .unwrap_or(f64::NEG_INFINITY);
+                    event!(
+                        Level::Info,
+                        stage = "search",
+                        host = host,
+                        chunk = chunk_file,
+                        rendition = rendition.name,
+                        crf = crf,
+                        score = score;
+                        "{} probed chunk {:?}{} with CRF {}: {} {:.4}",
+                        host,
+                        chunk,
+                        label,
+                        crf,
+                        target.metric,
+                        score
+                    );
+                    search.record(crf, score);
+                    last = Some(crf);
+                }
+
+                // The last probe is already sitting there if it's the one
+                let crf = search.chosen();
+                if last != Some(crf) && running.load(Ordering::SeqCst) {
+                    let args = quality::with_crf(args, crf);
+                    encode(
+                        &host, &dir, &chunk, &enc_name, &args, &label,
+                        &running,
+                    );
+                }
+                Some(crf)
+            }
+            _ => {
+                encode(&host, &dir, &chunk, &enc_name, args, &label, &running);
+                None
+            }
+        };
+
+        // Measure the quality while the source chunk is still around
+        let scores: Scores = metrics
+            .iter()
+            .filter_map(|&metric| {
+                // Reuse the score from the search if the last probe was it
+                let known = match (&search, crf, target) {
+                    (Some(search), Some(crf), Some(t))
+                        if t.metric == metric =>
+                    {
+                        search.score(crf)
+                    }
+                    _ => None,
+                };
+                known
+                    .or_else(|| {
+                        measure(
+                            &host, &dir, &chunk, &enc_name, metric, scaled,
+                            &running,
+                        )
+                    })
+                    .map(|score| (metric, score))
+            })
+            .collect();
+
+        // Hand the encoded chunk over to be transferred back
+        let encoded = Encoded {
+            task,
+            chunk: chunk_file.into_owned(),
+            rendition: rendition.name.clone(),
+            host: host.name.clone(),
+            name: enc_name,
+            scores,
+            crf,
+            elapsed: started.elapsed(),
+        };
+        if sender.send(encoded).is_err() {
+            // Returner stopped, so quit early
+            break;
+        }
+    }
+    debug!("Encoder thread {} exiting", host);
+}
+
+/// Throws away an encoded chunk that doesn't match its source and puts its
+/// task back into the queue. If it already failed too often or the same way
+/// before, the queue is told to fail the job instead.
+fn reject(
+    host: &Host,
+    chunk: Encoded,
+    local_path: &Path,
+    problem: &str,
+    queue: &Queue,
+) {
+    // Keep it out of the combined output
+    fs::remove_file(local_path).ok();
+    let mut task = chunk.task;
+    task.failures += 1;
+    // Encoding it again won't help if it comes out wrong the same way, as
+    // when the options don't fit the source
+    let failure = if task.problem.as_deref() == Some(problem) {
+        Some(format!(
+            "Encoded {} came out wrong the same way twice, the last time \
+             from {}: {}",
+            chunk.name, host, problem
+        ))
+    } else if task.failures >= ENCODE_ATTEMPTS {
+        Some(format!(
+            "Failed getting a valid encode of {} after {} attempts, the \
+             last from {}: {}",
+            chunk.name, ENCODE_ATTEMPTS, host, problem
+        ))
+    } else {
+        None
+    };
+    if let Some(failure) = failure {
+        queue.fail(failure);
+        return;
+    }
+    task.problem = Some(problem.to_string());
+    event!(
+        Level::Warn,
+        stage = "return",
+        host = host,
+        chunk = chunk.chunk,
+        rendition = chunk.rendition,
+        error = problem;
+        "Rejected encoded chunk {} from {} because {}, encoding it again \
+         (attempt {} of {})",
+        chunk.name,
+        host,
+        problem,
+        task.failures + 1,
+        ENCODE_ATTEMPTS
+    );
+    queue.put_back(task);
+}
 
-        // Encode the chunk remotely
-        info!("{} starts encoding chunk {:?}", host, chunk);
-        let output = Command::new("ssh")
-            .args(&command)
+/// Transfers a chunk to a host and verifies its checksum there, trying again
+/// if it arrived corrupted.
+fn transfer_chunk(host: &Host, dir: &str, chunk: &Path, running: &AtomicBool) {
+    let expected = checksum::hash_file(chunk).expect("Failed hashing chunk");
+    let remote_path = remote_name(dir, chunk);
+    for attempt in 1..=TRANSFER_ATTEMPTS {
+        let output = transfer_command(host, dir, chunk)
             .output()
-            .expect("Failed executing ssh command");
-        assert!(
-            output.status.success() || !running.load(Ordering::SeqCst),
-            "Failed encoding"
+            .expect("Failed executing scp command");
+        // Aborting also terminates our commands, so there's nothing to check
+        if !running.load(Ordering::SeqCst) {
+            return;
+        }
+        assert!(output.status.success(), "Failed transferring chunk");
+
+        if checksum::remote_hash(host, &remote_path) == Some(expected.clone())
+        {
+            return;
+        }
+        event!(
+            Level::Warn,
+            stage = "transfer",
+            host = host,
+            chunk = chunk.file_name().map(|f| f.to_string_lossy());
+            "Chunk {:?} arrived corrupted on {} (attempt {} of {})",
+            chunk,
+            host,
+            attempt,
+            TRANSFER_ATTEMPTS
         );
+    }
+    panic!("Failed transferring chunk {:?} to {} intact", chunk, host);
+}
 
-        // Remember the encoded chunk
-        encoded.push(enc_name);
+/// Transfers an encoded chunk back from a host and verifies it against the
+/// checksum computed there, trying again if it arrived corrupted.
+fn return_chunk(
+    host: &Host,
+    enc_name: &str,
+    encoded_dir: &Path,
+    running: &AtomicBool,
+) {
+    let expected = checksum::remote_hash(host, enc_name);
+    if !running.load(Ordering::SeqCst) {
+        return;
     }
-    debug!("Encoder thread {} exiting", host);
+    let expected = expected.expect("Failed hashing encoded chunk on host");
+    let file_name = Path::new(enc_name).file_name().expect("No normal file");
+    let local_path = encoded_dir.join(file_name);
+    for attempt in 1..=TRANSFER_ATTEMPTS {
+        let output = return_command(host, enc_name, encoded_dir)
+            .output()
+            .expect("Failed executing scp command");
+        if !running.load(Ordering::SeqCst) {
+            return;
+        }
+        assert!(output.status.success(), "Failed transferring encoded chunk");
 
-    encoded
+        if checksum::hash_file(&local_path).ok() == Some(expected.clone()) {
+            return;
+        }
+        event!(
+            Level::Warn,
+            stage = "return",
+            host = host,
+            chunk = file_name.to_string_lossy();
+            "Encoded chunk {} arrived corrupted from {} (attempt {} of {})",
+            enc_name,
+            host,
+            attempt,
+            TRANSFER_ATTEMPTS
+        );
+    }
+    panic!(
+        "Failed transferring encoded chunk {} from {} intact",
+        enc_name, host
+    );
+}
+
+/// Encodes a chunk on a host with the given `ffmpeg` arguments.
+fn encode(
+    host: &Host,
+    dir: &str,
+    chunk: &Path,
+    enc_name: &str,
+    args: &[String],
+    label: &str,
+    running: &AtomicBool,
+) {
+    let chunk_file = chunk.file_name().map(|f| f.to_string_lossy());
+    event!(
+        Level::Info,
+        stage = "encode",
+        host = host,
+        chunk = chunk_file;
+        "{} starts encoding chunk {:?}{}",
+        host,
+        chunk,
+        label
+    );
+    let start = Instant::now();
+    let output = encode_command(host, dir, chunk, enc_name, args)
+        .output()
+        .expect("Failed executing ssh command");
+    assert!(
+        output.status.success() || !running.load(Ordering::SeqCst),
+        "Failed encoding"
+    );
+    let duration = start.elapsed();
+    event!(
+        Level::Info,
+        stage = "encode",
+        host = host,
+        chunk = chunk_file,
+        duration = duration.as_secs_f64();
+        "{} finished encoding chunk {:?}{} in {:.1?}",
+        host,
+        chunk,
+        label,
+        duration
+    );
+}
+
+/// Runs the first pass over a chunk on a host with the given `ffmpeg`
+/// arguments.
+fn first_pass(
+    host: &Host,
+    dir: &str,
+    chunk: &Path,
+    args: &[String],
+    label: &str,
+    running: &AtomicBool,
+) {
+    let chunk_file = chunk.file_name().map(|f| f.to_string_lossy());
+    event!(
+        Level::Info,
+        stage = "analyse",
+        host = host,
+        chunk = chunk_file;
+        "{} starts the first pass over chunk {:?}{}",
+        host,
+        chunk,
+        label
+    );
+    let start = Instant::now();
+    let output = budget::first_pass_command(host, dir, chunk, args)
+        .output()
+        .expect("Failed executing ssh command");
+    assert!(
+        output.status.success() || !running.load(Ordering::SeqCst),
+        "Failed the first pass"
+    );
+    let duration = start.elapsed();
+    event!(
+        Level::Info,
+        stage = "analyse",
+        host = host,
+        chunk = chunk_file,
+        duration = duration.as_secs_f64();
+        "{} finished the first pass over chunk {:?}{} in {:.1?}",
+        host,
+        chunk,
+        label,
+        duration
+    );
+}
+
+/// Measures the quality of an encoded chunk on a host, warning if it fails.
+///
+/// If the chunk was `scaled`, it's compared at the size of the source.
+fn measure(
+    host: &Host,
+    dir: &str,
+    chunk: &Path,
+    enc_name: &str,
+    metric: Metric,
+    scaled: bool,
+    running: &AtomicBool,
+) -> Option<f64> {
+    let chunk_name = remote_name(dir, chunk);
+    let score = quality::measure(host, enc_name, &chunk_name, metric, scaled);
+    if score.is_none() && running.load(Ordering::SeqCst) {
+        event!(
+            Level::Warn,
+            stage = "quality",
+            host = host,
+            chunk = chunk.file_name().map(|f| f.to_string_lossy());
+            "{} failed computing {} of {}",
+            host,
+            metric,
+            enc_name
+        );
+    }
+    score
+}
+
+/// Returns the name of a job's temporary directory on the hosts.
+pub fn tmp_dir(job: &str) -> String {
+    format!("{}_{}", TMP_DIR, job)
+}
+
+/// Builds the `ssh` command running a program with arguments on a host.
+///
+/// `ssh` joins everything after the host into a single string that the
+/// remote shell splits again, so every argument is quoted to arrive
+/// unchanged.
+pub fn ssh_command<I, S>(host: &Host, args: I) -> Command
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let mut command = Command::new("ssh");
+    command.args(host.ssh_options()).arg(&host.name);
+    command.args(args.into_iter().map(|arg| shell::quote(arg.as_ref())));
+    command
+}
+
+/// Builds an `scp` command with the options to connect to a host.
+fn scp_command(host: &Host) -> Command {
+    let mut command = Command::new("scp");
+    command.args(host.ssh_options());
+    command
+}
+
+/// Builds the `ssh` command closing the connection to a host that the
+/// commands of a job share.
+pub fn close_command(host: &Host) -> Command {
+    let mut command = Command::new("ssh");
+    command
+        .args(host.ssh_options())
+        .args(["-O", "exit", &host.name]);
+    command
+}
+
+/// Builds the `ssh` command removing the temporary directory on a host.
+pub fn cleanup_command(host: &Host, dir: &str) -> Command {
+    ssh_command(host, ["rm", "-r", dir])
+}
+
+/// Builds the `ssh` command removing the temporary directories of crashed
+/// jobs on a host, whether they're there or not.
+pub fn purge_command(host: &Host, dirs: &[String]) -> Command {
+    let dirs = dirs.iter().map(String::as_str);
+    ssh_command(host, ["rm", "-rf"].iter().copied().chain(dirs))
+}
+
+/// Builds the `ssh` command creating the temporary directory on a host.
+pub fn mkdir_command(host: &Host, dir: &str) -> Command {
+    ssh_command(host, ["mkdir", dir])
+}
+
+/// Builds the `scp` command transferring a chunk to a host.
+pub fn transfer_command(host: &Host, dir: &str, chunk: &Path) -> Command {
+    let mut command = scp_command(host);
+    command.arg(chunk).arg(format!("{}:{}", host, dir));
+    command
+}
+
+/// Builds the `ssh` command encoding a chunk on a host.
+pub fn encode_command(
+    host: &Host,
+    dir: &str,
+    chunk: &Path,
+    enc_name: &str,
+    args: &[String],
+) -> Command {
+    let input = remote_name(dir, chunk);
+    let mut remote_args = vec!["ffmpeg", "-y", "-i", &input];
+    remote_args.extend(args.iter().map(String::as_str));
+    remote_args.push(enc_name);
+    ssh_command(host, remote_args)
+}
+
+/// Builds the `scp` command returning an encoded chunk from a host.
+pub fn return_command(
+    host: &Host,
+    enc_name: &str,
+    encoded_dir: &Path,
+) -> Command {
+    let mut command = scp_command(host);
+    command
+        .arg(format!("{}:{}", host, enc_name))
+        .arg(encoded_dir);
+    command
+}
+
+/// Returns the remote file name of a chunk.
+///
+/// Chunks have ASCII names, regardless of the input's, so the conversion is
+/// lossless.
+pub fn remote_name(dir: &str, chunk: &Path) -> String {
+    format!(
+        "{}/{}",
+        dir,
+        chunk.file_name().expect("No normal file").to_string_lossy()
+    )
+}
+
+/// Returns the remote file name of the version of a chunk encoded for a
+/// rendition.
+pub fn encoded_name(
+    dir: &str,
+    chunk: &Path,
+    rendition: &str,
+    out_ext: &str,
+) -> String {
+    format!("{}/{}", dir, encoded_file_name(chunk, rendition, out_ext))
+}
+
+/// Returns the name of the version of a chunk encoded for a rendition, which
+/// it keeps when it's returned.
+pub fn encoded_file_name(
+    chunk: &Path,
+    rendition: &str,
+    out_ext: &str,
+) -> String {
+    format!(
+        "enc_{}_{}.{}",
+        rendition,
+        chunk.file_stem().expect("No normal file").to_string_lossy(),
+        out_ext
+    )
 }
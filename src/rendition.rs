@@ -0,0 +1,97 @@
+//! The description of renditions, the versions of the video a job produces.
+
+use std::{collections::HashSet, path::PathBuf};
+
+/// The name of the rendition of a job that doesn't list any.
+pub const DEFAULT_NAME: &str = "main";
+
+/// A version of the video, encoded from the same chunks as the others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rendition {
+    /// The name, which goes into the file names of its encoded chunks.
+    pub name: String,
+    /// The size to scale to as for `ffmpeg`'s scale filter, e.g. `-2:720`.
+    pub scale: Option<String>,
+    /// Arguments to `ffmpeg` for chunk encoding, the job's if empty.
+    pub args: Vec<String>,
+    /// The path to the output file.
+    pub output: PathBuf,
+}
+
+impl Rendition {
+    /// Creates a rendition without scaling that uses the job's arguments.
+    pub fn new(name: impl Into<String>, output: impl Into<PathBuf>) -> Self {
+        Self {
+            name: name.into(),
+            scale: None,
+            args: Vec::new(),
+            output: output.into(),
+        }
+    }
+
+    /// Parses renditions from lines of the form
+    /// `name scale output [ffmpeg options ...]`.
+    ///
+    /// The scale can be `-` to keep the size of the input. Options are split
+    /// at whitespace, so they can't contain any.
+    pub fn from_lines(lines: &str) -> Result<Vec<Self>, String> {
+        let mut names = HashSet::new();
+        super::parse_lines(lines, |line| {
+            let rendition = Self::from_line(line)?;
+            if names.insert(rendition.name.clone()) {
+                Ok(rendition)
+            } else {
+                Err(format!("Duplicate name {}", rendition.name))
+            }
+        })
+    }
+
+    /// Parses a single line of a renditions file.
+    fn from_line(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let mut next = |what| {
+            words
+                .next()
+                .ok_or_else(|| format!("Missing {} in {}", what, line))
+        };
+        let name = next("name")?;
+        let scale = next("scale")?;
+        let output = next("output")?;
+        // The name ends up in file names on the hosts
+        let is_safe = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_".contains(c));
+        if !is_safe {
+            return Err(format!(
+                "Name {} may only contain ASCII letters, digits, - and _",
+                name
+            ));
+        }
+
+        Ok(Self {
+            scale: Some(scale)
+                .filter(|&scale| scale != "-")
+                .map(ToString::to_string),
+            args: words.map(ToString::to_string).collect(),
+            ..Self::new(name, output)
+        })
+    }
+
+    /// Returns the `ffmpeg` arguments for encoding chunks, including the
+    /// scaling.
+    ///
+    /// If the arguments already have a filter chain, the scaling goes first.
+    pub fn encode_args(&self) -> Vec<String> {
+        let mut args = self.args.clone();
+        if let Some(scale) = &self.scale {
+            let scale = format!("scale={}", scale);
+            match args.iter().position(|arg| arg == "-vf") {
+                Some(i) if i + 1 < args.len() => {
+                    args[i + 1] = format!("{},{}", scale, args[i + 1]);
+                }
+                _ => args.extend(["-vf".to_string(), scale]),
+            }
+        }
+        args
+    }
+}
@@ -0,0 +1,241 @@
+//! Functions for encoding samples of the input to preview settings.
+
+use log::Level;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::{
+    local, remote, Config, Encoded, Host, MediaInfo, Rendition, Result,
+};
+
+/// The number of bytes in a mebibyte, which sizes are given in.
+const MIB: f64 = (1 << 20) as f64;
+
+/// How to sample the input instead of encoding all of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    /// The number of samples, which are spread evenly over the input.
+    pub count: u32,
+    /// The length of every sample in seconds.
+    pub length: f64,
+    /// Whether to keep the encoded samples next to the outputs.
+    pub keep: bool,
+}
+
+impl Sample {
+    /// Creates a sampling of `count` excerpts of `length` seconds, which
+    /// aren't kept.
+    pub fn new(count: u32, length: f64) -> Self {
+        Self {
+            count,
+            length,
+            keep: false,
+        }
+    }
+
+    /// Returns where the samples start in seconds, spread evenly over
+    /// `duration` seconds from `offset` on and rounded to milliseconds.
+    pub fn starts(self, offset: f64, duration: f64) -> Vec<f64> {
+        let room = (duration - self.length).max(0.0);
+        (0..self.count)
+            .map(|i| offset + room * (i as f64 + 0.5) / self.count as f64)
+            .map(|start| (start * 1000.0).round() / 1000.0)
+            .collect()
+    }
+
+    /// Copies the samples of the input into chunks in `chunk_dir`, which are
+    /// returned with their lengths.
+    ///
+    /// Samples start at the keyframe before where they should, so their
+    /// lengths are probed rather than taken for granted.
+    pub fn extract(
+        self,
+        input: &Path,
+        chunk_dir: &Path,
+        starts: &[f64],
+        running: &Arc<AtomicBool>,
+    ) -> Result<Vec<(PathBuf, f64)>> {
+        let extension = local::chunk_extension(input)?;
+        let mut samples = Vec::new();
+        for (i, &start) in starts.iter().enumerate() {
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+            let chunk =
+                chunk_dir.join(format!("chunk_{:03}.{}", i, extension));
+            local::extract_sample(input, &chunk, start, self.length, running)?;
+            let length = MediaInfo::probe(&chunk)?.duration;
+            samples.push((chunk, length));
+        }
+
+        Ok(samples)
+    }
+
+    /// Logs how fast every host encoded the `samples` and projects the time
+    /// it would take to encode the input described by `info` and the size of
+    /// every output, keeping the encoded samples if asked to.
+    pub fn report(
+        self,
+        config: &Config,
+        info: &MediaInfo,
+        hosts: &[Host],
+        samples: &[(PathBuf, f64)],
+        encoded: &[Encoded],
+        encoded_dir: &Path,
+    ) -> Result<()> {
+        let length_of = |chunk: &Path| {
+            samples
+                .iter()
+                .find(|(sample, _)| sample == chunk)
+                .map_or(0.0, |(_, length)| *length)
+        };
+
+        // Every encoder of a host adds its speed to the total
+        let mut names: Vec<&str> =
+            encoded.iter().map(|chunk| chunk.host.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        let mut throughput = 0.0;
+        for name in names {
+            let chunks = encoded.iter().filter(|chunk| chunk.host == name);
+            let (seconds, elapsed) =
+                chunks.fold((0.0, 0.0), |(seconds, elapsed), chunk| {
+                    (
+                        seconds + length_of(&chunk.task.chunk),
+                        elapsed + chunk.elapsed.as_secs_f64(),
+                    )
+                });
+            if elapsed <= 0.0 {
+                continue;
+            }
+            let speed = seconds / elapsed;
+            let slots = hosts
+                .iter()
+                .find(|host| host.name == name)
+                .map_or(1, |host| host.slots);
+            throughput += speed * slots as f64;
+            let fps = info
+                .video
+                .frame_rate
+                .map(|rate| format!(" ({:.1} fps)", speed * rate))
+                .unwrap_or_default();
+            let encoders = if slots > 1 {
+                format!(" with each of its {} encoders", slots)
+            } else {
+                String::new()
+            };
+            event!(
+                Level::Info,
+                stage = "sample",
+                host = name,
+                speed = speed;
+                "{} encodes at {:.2}x realtime{}{}",
+                name,
+                speed,
+                fps,
+                encoders
+            );
+        }
+        if throughput > 0.0 {
+            let total = Duration::from_secs_f64(
+                info.duration * config.renditions.len() as f64 / throughput,
+            );
+            event!(
+                Level::Info,
+                stage = "sample",
+                duration = total.as_secs_f64();
+                "Encoding all {:.2} s on these hosts would take about {:.0?}",
+                info.duration,
+                total
+            );
+        }
+
+        for rendition in &config.renditions {
+            self.project(config, info, rendition, samples, encoded_dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// Logs the size and bitrate the output of a rendition would have, going
+    /// by its encoded samples, and keeps them if asked to.
+    fn project(
+        self,
+        config: &Config,
+        info: &MediaInfo,
+        rendition: &Rendition,
+        samples: &[(PathBuf, f64)],
+        encoded_dir: &Path,
+    ) -> Result<()> {
+        let out_ext = super::output_extension(&rendition.output)?;
+        let rendition_dir = encoded_dir.join(&rendition.name);
+        let label = config.label(rendition);
+        let mut bytes = 0;
+        let mut seconds = 0.0;
+        for (i, (chunk, length)) in samples.iter().enumerate() {
+            let encoded = rendition_dir.join(remote::encoded_file_name(
+                chunk,
+                &rendition.name,
+                &out_ext,
+            ));
+            bytes += fs::metadata(&encoded)?.len();
+            seconds += length;
+            if self.keep {
+                fs::copy(&encoded, kept_path(&rendition.output, &out_ext, i))?;
+            }
+        }
+        if self.keep && !samples.is_empty() {
+            event!(
+                Level::Info,
+                stage = "sample",
+                rendition = rendition.name;
+                "Kept the samples{} as {} and so on",
+                label,
+                kept_path(&rendition.output, &out_ext, 0).display()
+            );
+        }
+        if seconds <= 0.0 {
+            return Ok(());
+        }
+
+        // The audio is encoded with a constant bitrate
+        let video = bytes as f64 * 8.0 / seconds;
+        let rate = video + info.audio_bit_rate() as f64;
+        let size = rate * info.duration / 8.0;
+        event!(
+            Level::Info,
+            stage = "sample",
+            rendition = rendition.name,
+            size = size,
+            bit_rate = rate;
+            "{} would be about {:.1} MiB at {:.0} kb/s, of which the video \
+             is {:.0} kb/s",
+            rendition.output.display(),
+            size / MIB,
+            rate / 1000.0,
+            video / 1000.0
+        );
+
+        Ok(())
+    }
+}
+
+/// Returns where to keep the encoded sample `index` of an output, next to
+/// it and numbered from 1.
+pub fn kept_path(output: &Path, extension: &str, index: usize) -> PathBuf {
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    output.with_file_name(format!(
+        "{}_sample_{:02}.{}",
+        stem,
+        index + 1,
+        extension
+    ))
+}
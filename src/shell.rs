@@ -0,0 +1,52 @@
+//! Functions for passing arguments through a shell.
+
+/// Quotes a word for a POSIX shell if it contains special characters.
+pub fn quote(word: &str) -> String {
+    let is_plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=+,%@".contains(c));
+    if is_plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_words_stay_as_they_are() {
+        assert_eq!(
+            quote("scale=1280:-2,format=yuv420p"),
+            "scale=1280:-2,format=yuv420p"
+        );
+        assert_eq!(quote("-c:v"), "-c:v");
+    }
+
+    #[test]
+    fn special_characters_are_quoted() {
+        assert_eq!(quote("title=\"My Film\""), "'title=\"My Film\"'");
+        assert_eq!(
+            quote("expr:gte(t,n_forced*6)"),
+            "'expr:gte(t,n_forced*6)'"
+        );
+    }
+
+    #[test]
+    fn single_quotes_are_escaped() {
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn empty_word_is_kept() {
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn non_ascii_is_quoted() {
+        assert_eq!(quote("Café"), "'Café'");
+    }
+}
@@ -0,0 +1,128 @@
+//! Placeholders in the encoding arguments that differ between chunks.
+
+use std::path::PathBuf;
+
+use super::{MediaInfo, Result};
+
+/// Where a chunk is in the input.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    /// The number of the chunk, counting from 0.
+    pub index: usize,
+    /// Where the chunk starts in the input in seconds.
+    pub start: f64,
+    /// The length of the chunk in seconds.
+    pub duration: f64,
+    /// Where the encoded part of the input starts in seconds, which is the
+    /// start of the output.
+    pub offset: f64,
+}
+
+impl Span {
+    /// Finds the spans of the `chunks` split from the input from `offset`
+    /// seconds on, which follow one another.
+    ///
+    /// The cuts happen at keyframes, so the lengths are probed rather than
+    /// taken from the chunk length.
+    pub fn probe(chunks: &[PathBuf], offset: f64) -> Result<Vec<Self>> {
+        let mut start = offset;
+        let mut spans = Vec::with_capacity(chunks.len());
+        for (index, chunk) in chunks.iter().enumerate() {
+            let duration = MediaInfo::probe(chunk)?.duration;
+            spans.push(Self {
+                index,
+                start,
+                duration,
+                offset,
+            });
+            start += duration;
+        }
+
+        Ok(spans)
+    }
+}
+
+/// Replaces `{index}`, `{start}`, `{duration}` and `{host}` in `args` with
+/// the values for a chunk that's encoded on a host.
+///
+/// Anything else in braces is left alone, since `ffmpeg` uses them as well,
+/// e.g. in `drawtext`.
+pub fn expand(args: &[String], span: Span, host: &str) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            arg.replace("{index}", &span.index.to_string())
+                .replace("{start}", &seconds(span.start))
+                .replace("{duration}", &seconds(span.duration))
+                .replace("{host}", host)
+        })
+        .collect()
+}
+
+/// Formats a time in seconds, rounded to milliseconds so sums of lengths
+/// don't show their rounding errors.
+fn seconds(time: f64) -> String {
+    ((time * 1000.0).round() / 1000.0).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let span = Span {
+            index: 3,
+            start: 30.03,
+            duration: 10.01,
+            offset: 0.0,
+        };
+        assert_eq!(
+            expand(
+                &args(&[
+                    "-metadata",
+                    "comment=chunk {index} at {start} s for {duration} s",
+                    "-x264-params",
+                    "stats={host}_{index}.log",
+                ]),
+                span,
+                "bigbox"
+            ),
+            args(&[
+                "-metadata",
+                "comment=chunk 3 at 30.03 s for 10.01 s",
+                "-x264-params",
+                "stats=bigbox_3.log",
+            ])
+        );
+    }
+
+    #[test]
+    fn rounds_times_to_milliseconds() {
+        let span = Span {
+            start: 0.1 + 0.2,
+            duration: 10.0 / 3.0,
+            ..Span::default()
+        };
+        assert_eq!(
+            expand(&args(&["{start}", "{duration}"]), span, "laptop"),
+            args(&["0.3", "3.333"])
+        );
+    }
+
+    #[test]
+    fn leaves_other_braces_alone() {
+        let drawtext = "drawtext=text='%{pts\\:hms} {index}':x=10:y=10";
+        assert_eq!(
+            expand(&args(&["-vf", drawtext, "{other}"]), Span::default(), "a"),
+            args(&[
+                "-vf",
+                "drawtext=text='%{pts\\:hms} 0':x=10:y=10",
+                "{other}"
+            ])
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Functions for checking the combined output against the source.
+
+use log::Level;
+use serde_json::Value;
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Arc,
+};
+
+use super::{range::Range, Result};
+
+/// How far apart durations may be in seconds. Containers round timestamps
+/// and the audio encoder pads its last frame, so they're rarely exact.
+pub const TOLERANCE: f64 = 0.1;
+
+/// The output doesn't match the source, so the job failed even though every
+/// command succeeded.
+#[derive(Debug)]
+pub struct Mismatch(String);
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for Mismatch {}
+
+/// What probing a file tells us about it.
+#[derive(Debug, Default)]
+struct Probe {
+    /// The duration of the file in seconds.
+    duration: f64,
+    /// The duration of the first video stream in seconds, if it's known.
+    video: Option<f64>,
+    /// The duration of the first audio stream in seconds, if it's known.
+    audio: Option<f64>,
+    /// The number of frames of the first video stream.
+    frames: u64,
+    /// The nominal frame rate of the first video stream, if it's known.
+    frame_rate: Option<f64>,
+}
+
+impl Probe {
+    /// Checks that the video has as many frames as its `source`, returning
+    /// what's wrong otherwise.
+    ///
+    /// The encoding options may change the frame rate, which changes the
+    /// count by the ratio of the nominal rates. Decoders drop the leading
+    /// frames of an open GOP, so the count is only checked as closely as the
+    /// durations are.
+    fn check_frames(&self, source: &Probe) -> std::result::Result<(), String> {
+        let scale = match (self.frame_rate, source.frame_rate) {
+            (Some(rate), Some(source)) => rate / source,
+            _ => 1.0,
+        };
+        let expected = (source.frames as f64 * scale).round() as u64;
+        let slack = self
+            .frame_rate
+            .map_or(1, |rate| (TOLERANCE * rate).ceil().max(1.0) as u64);
+        if self.frames.abs_diff(expected) > slack {
+            return Err(format!(
+                "it has {} frames instead of about {}",
+                self.frames, expected
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns how much longer the audio is than the video, if both are
+    /// known.
+    fn audio_offset(&self) -> Option<f64> {
+        Some(self.audio? - self.video?)
+    }
+}
+
+/// The source of a job, which the output of every rendition is checked
+/// against.
+pub struct Source {
+    /// The probe of the input.
+    input: Probe,
+    /// The source chunks with their probes, in order.
+    chunks: Vec<(PathBuf, Probe)>,
+}
+
+impl Source {
+    /// Probes the input and its chunks.
+    ///
+    /// If only a `range` of the input is encoded, the probe of the input
+    /// doesn't apply. Then the output needs to have the length of the range.
+    pub fn probe(
+        input: &Path,
+        chunks: &[PathBuf],
+        range: Option<Range>,
+        running: &Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok((chunk.clone(), probe(chunk, false, running)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let input = match range {
+            Some(range) => Probe {
+                duration: range.length(),
+                ..Probe::default()
+            },
+            None => probe(input, false, running)?,
+        };
+
+        Ok(Self { input, chunks })
+    }
+
+    /// Checks the `encoded` chunks, which are in the same order as the source
+    /// chunks, and the `output` of a rendition.
+    ///
+    /// Every chunk needs to have as many frames as its source at its frame
+    /// rate, and the output all of their frames and the duration of the input. The audio and video of the
+    /// output may not be further apart than in the input.
+    /// Each problem is logged, and if there are any, a [`Mismatch`] returned.
+    pub fn check(
+        &self,
+        encoded: &[PathBuf],
+        output: &Path,
+        rendition: &str,
+        label: &str,
+        running: &Arc<AtomicBool>,
+    ) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut frames = 0;
+        for ((chunk, source), encoded) in self.chunks.iter().zip(encoded) {
+            let name = chunk.file_name().unwrap_or_default().to_string_lossy();
+            if !encoded.exists() {
+                problems.push(format!("Encoded {}{} is missing", name, label));
+                continue;
+            }
+            let probe = probe(encoded, false, running)?;
+            frames += probe.frames;
+            if let Err(problem) = probe.check_frames(source) {
+                problems
+                    .push(format!("Encoded {}{}: {}", name, label, problem));
+            }
+        }
+        let probe = probe(output, false, running)?;
+        if !running.load(Ordering::SeqCst) {
+            // Probing was interrupted, so there's nothing to judge
+            return Ok(());
+        }
+
+        // The chunks are copied into the output, so not a single frame may
+        // go missing on the way
+        if probe.frames != frames {
+            problems.push(format!(
+                "{} has {} frames instead of the {} of its chunks",
+                output.display(),
+                probe.frames,
+                frames
+            ));
+        }
+        if (probe.duration - self.input.duration).abs() > TOLERANCE {
+            problems.push(format!(
+                "{} is {:.3} s long instead of {:.3} s",
+                output.display(),
+                probe.duration,
+                self.input.duration
+            ));
+        }
+        // Packages don't always tell the durations of their streams
+        if let Some(offset) = probe.audio_offset() {
+            let expected = self.input.audio_offset().unwrap_or_default();
+            if (offset - expected).abs() > TOLERANCE {
+                problems.push(format!(
+                    "The audio of {} is {:.3} s longer than the video instead \
+                     of {:.3} s",
+                    output.display(),
+                    offset,
+                    expected
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            event!(
+                Level::Info,
+                stage = "verify",
+                rendition = rendition;
+                "{} matches the source",
+                output.display()
+            );
+            return Ok(());
+        }
+        for problem in &problems {
+            event!(
+                Level::Error,
+                stage = "verify",
+                rendition = rendition,
+                error = problem.as_str();
+                "{}",
+                problem
+            );
+        }
+        Err(Box::new(Mismatch(format!(
+            "{} doesn't match the source",
+            output.display()
+        ))))
+    }
+}
+
+/// Checks that an encoded chunk decodes and has the duration and the frames
+/// of its source chunk, returning what's wrong with it otherwise.
+pub fn check_chunk(
+    source: &Path,
+    encoded: &Path,
+    running: &Arc<AtomicBool>,
+) -> std::result::Result<(), String> {
+    let source = probe(source, false, running).map_err(|e| e.to_string())?;
+    let encoded = probe(encoded, true, running).map_err(|e| e.to_string())?;
+    if !running.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    let length = encoded.video.unwrap_or(encoded.duration);
+    let expected = source.video.unwrap_or(source.duration);
+    if (length - expected).abs() > TOLERANCE {
+        return Err(format!(
+            "it's {:.3} s long instead of {:.3} s",
+            length, expected
+        ));
+    }
+
+    encoded.check_frames(&source)
+}
+
+/// Uses `ffprobe` to find the durations and the number of video frames of a
+/// file, decoding every frame if `decode` is set.
+///
+/// When decoding, any error counts as a failure. If we've been aborted in the
+/// meantime, an empty probe is returned.
+fn probe(
+    path: &Path,
+    decode: bool,
+    running: &Arc<AtomicBool>,
+) -> Result<Probe> {
+    let output = probe_command(path, decode).output()?;
+    if !running.load(Ordering::SeqCst) {
+        return Ok(Probe::default());
+    }
+    let errors = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() || (decode && !errors.trim().is_empty()) {
+        return Err(format!(
+            "Failed probing {}: {}",
+            path.display(),
+            errors.lines().next().unwrap_or_default()
+        )
+        .into());
+    }
+    let json: Value = serde_json::from_slice(&output.stdout)?;
+
+    let streams = json["streams"].as_array().cloned().unwrap_or_default();
+    let stream = |codec_type: &str| {
+        streams
+            .iter()
+            .find(|stream| stream["codec_type"] == codec_type)
+    };
+    // Matroska only has the duration of streams in their tags
+    let duration = |stream: &Value| {
+        seconds(&stream["duration"])
+            .or_else(|| timestamp(stream["tags"]["DURATION"].as_str()?))
+    };
+    let video = stream("video").ok_or("Found no video stream")?;
+
+    Ok(Probe {
+        duration: seconds(&json["format"]["duration"])
+            .ok_or("Found no duration")?,
+        video: duration(video),
+        audio: stream("audio").and_then(duration),
+        frames: video[frames_entry(decode)]
+            .as_str()
+            .and_then(|frames| frames.parse().ok())
+            .ok_or("Found no frame count")?,
+        frame_rate: rate(&video["r_frame_rate"]),
+    })
+}
+
+/// Builds the `ffprobe` command for finding the durations and the number of
+/// video frames of a file.
+///
+/// Unless we `decode` to make sure every frame does, packets are counted
+/// instead, which is much faster and the same for video.
+pub fn probe_command(path: &Path, decode: bool) -> Command {
+    let mut command = Command::new("ffprobe");
+    command.args([
+        "-v",
+        "error",
+        if decode {
+            "-count_frames"
+        } else {
+            "-count_packets"
+        },
+        "-show_entries",
+        &format!(
+            "format=duration:stream=codec_type,duration,r_frame_rate,{}:\
+             stream_tags=DURATION",
+            frames_entry(decode)
+        ),
+        "-of",
+        "json",
+    ]);
+    command.arg(path);
+    command
+}
+
+/// Returns the entry of a stream that has its number of frames.
+fn frames_entry(decode: bool) -> &'static str {
+    if decode {
+        "nb_read_frames"
+    } else {
+        "nb_read_packets"
+    }
+}
+
+/// Parses a number of seconds, which `ffprobe` gives as a string.
+fn seconds(value: &Value) -> Option<f64> {
+    value.as_str()?.parse().ok()
+}
+
+/// Parses a frame rate, which `ffprobe` gives as a fraction that's `0/0` if
+/// it's unknown.
+fn rate(value: &Value) -> Option<f64> {
+    let (numerator, denominator) = value.as_str()?.split_once('/')?;
+    let rate =
+        numerator.parse::<f64>().ok()? / denominator.parse::<f64>().ok()?;
+    Some(rate).filter(|rate| rate.is_finite() && *rate > 0.0)
+}
+
+/// Parses a timestamp of the form `HH:MM:SS.fraction` into seconds.
+fn timestamp(timestamp: &str) -> Option<f64> {
+    let mut parts = timestamp.splitn(3, ':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
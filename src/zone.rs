@@ -0,0 +1,242 @@
+//! Time ranges of the input that are encoded with their own settings.
+
+use std::fmt;
+
+use super::{range, template::Span};
+
+/// A time range of the input in seconds with `ffmpeg` options that change
+/// those of the job.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    /// Where the zone starts.
+    pub start: f64,
+    /// Where the zone ends, the end of the input if `None`.
+    pub end: Option<f64>,
+    /// The options that replace or add to the job's.
+    pub args: Vec<String>,
+}
+
+impl Zone {
+    /// Parses zones from lines of the form `start end ffmpeg options ...`.
+    ///
+    /// Times are in seconds or of the form `[HH:]MM:SS[.fraction]`, and the
+    /// end can be `-` for the end of the input. Zones can't overlap.
+    pub fn from_lines(lines: &str) -> Result<Vec<Self>, String> {
+        let mut zones = super::parse_lines(lines, Self::from_line)?;
+        zones.sort_by(|a, b| a.start.total_cmp(&b.start));
+        for pair in zones.windows(2) {
+            let (zone, next) = (&pair[0], &pair[1]);
+            if zone.end.is_none_or(|end| end > next.start) {
+                return Err(format!(
+                    "Zone from {} overlaps the one from {}",
+                    next, zone
+                ));
+            }
+        }
+
+        Ok(zones)
+    }
+
+    /// Parses a single line of a zones file.
+    fn from_line(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let mut next = |what| {
+            words
+                .next()
+                .ok_or_else(|| format!("Missing {} in {}", what, line))
+        };
+        let start = range::parse_time(next("start")?)?;
+        let end = match next("end")? {
+            "-" => None,
+            end => Some(range::parse_time(end)?),
+        };
+        if let Some(end) = end.filter(|&end| end <= start) {
+            return Err(format!(
+                "End at {:.2} s needs to be after the start at {:.2} s",
+                end, start
+            ));
+        }
+        let args: Vec<String> = words.map(ToString::to_string).collect();
+        if args.is_empty() {
+            return Err(format!("Missing ffmpeg options in {}", line));
+        }
+
+        Ok(Self { start, end, args })
+    }
+
+    /// Returns whether a chunk is in the zone, which is when the zone has
+    /// more than half of it.
+    pub fn contains(&self, span: Span) -> bool {
+        let end = self.end.unwrap_or(f64::INFINITY);
+        let overlap =
+            end.min(span.start + span.duration) - self.start.max(span.start);
+        overlap > span.duration / 2.0
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{:.2} s to {:.2} s", self.start, end),
+            None => write!(f, "{:.2} s to the end", self.start),
+        }
+    }
+}
+
+/// Returns the zone a chunk is in, if any.
+pub fn find(zones: &[Zone], span: Span) -> Option<&Zone> {
+    zones.iter().find(|zone| zone.contains(span))
+}
+
+/// Returns where to split `duration` seconds of the input from `offset` on
+/// into chunks of about `seconds`, so the chunks start and end with the
+/// zones. The times are counted from `offset`.
+///
+/// The parts between zone boundaries are divided evenly, so there are no
+/// slivers of chunks at the end of a zone.
+pub fn cuts(
+    zones: &[Zone],
+    seconds: f64,
+    offset: f64,
+    duration: f64,
+) -> Vec<f64> {
+    let mut edges: Vec<f64> = zones
+        .iter()
+        .flat_map(|zone| Some(zone.start).into_iter().chain(zone.end))
+        .map(|time| time - offset)
+        .filter(|&time| time > 0.0 && time < duration)
+        .collect();
+    edges.push(duration);
+    edges.dedup();
+
+    let mut cuts = Vec::new();
+    let mut start = 0.0;
+    for edge in edges {
+        let count = ((edge - start) / seconds).ceil().max(1.0);
+        let length = (edge - start) / count;
+        cuts.extend((1..count as u32).map(|i| start + i as f64 * length));
+        cuts.push(edge);
+        start = edge;
+    }
+    // The last chunk ends with the input
+    cuts.pop();
+    cuts.iter()
+        .map(|time| (time * 1000.0).round() / 1000.0)
+        .collect()
+}
+
+/// Returns the `ffmpeg` arguments with the options of a zone, which replace
+/// the values of those that are already there and are added otherwise.
+pub fn apply(args: &[String], options: &[String]) -> Vec<String> {
+    let is_option =
+        |arg: &str| arg.starts_with('-') && arg.parse::<f64>().is_err();
+    let mut args = args.to_vec();
+    let mut options = options.iter().peekable();
+    while let Some(option) = options.next() {
+        // Flags are followed by the next option rather than a value
+        let value = options.next_if(|next| !is_option(next));
+        match (args.iter().position(|arg| arg == option), value) {
+            (Some(i), Some(value)) if i + 1 < args.len() => {
+                args[i + 1] = value.clone()
+            }
+            (Some(_), None) => {}
+            (_, value) => {
+                args.push(option.clone());
+                args.extend(value.cloned());
+            }
+        }
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn parses_and_sorts_zones() {
+        let zones =
+            Zone::from_lines("# credits\n1:00 - -crf 32\n\n0 30 -an").unwrap();
+        assert_eq!(
+            zones,
+            [
+                Zone {
+                    start: 0.0,
+                    end: Some(30.0),
+                    args: strings(&["-an"]),
+                },
+                Zone {
+                    start: 60.0,
+                    end: None,
+                    args: strings(&["-crf", "32"]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_overlapping_zones() {
+        assert!(Zone::from_lines("0 1:30 -crf 30\n1:00 - -crf 32").is_err());
+        assert!(Zone::from_lines("30 10 -crf 30").is_err());
+        assert!(Zone::from_lines("0 10").is_err());
+    }
+
+    #[test]
+    fn finds_the_zone_with_most_of_a_chunk() {
+        let zones = Zone::from_lines("0 10 -an\n50 - -crf 30").unwrap();
+        let span = |start, duration| Span {
+            start,
+            duration,
+            ..Span::default()
+        };
+        assert_eq!(find(&zones, span(4.0, 10.0)), Some(&zones[0]));
+        assert_eq!(find(&zones, span(6.0, 10.0)), None);
+        assert_eq!(find(&zones, span(44.0, 10.0)), None);
+        assert_eq!(find(&zones, span(46.0, 10.0)), Some(&zones[1]));
+    }
+
+    #[test]
+    fn cuts_at_zone_boundaries() {
+        let zones = Zone::from_lines("30 60 -crf 30").unwrap();
+        assert_eq!(
+            cuts(&zones, 10.0, 0.0, 100.0),
+            [10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0]
+        );
+    }
+
+    #[test]
+    fn divides_parts_evenly() {
+        assert_eq!(cuts(&[], 40.0, 0.0, 100.0), [33.333, 66.667]);
+    }
+
+    #[test]
+    fn cuts_from_the_offset() {
+        let zones = Zone::from_lines("0 10 -an\n50 - -crf 30").unwrap();
+        assert_eq!(cuts(&zones, 50.0, 20.0, 100.0), [30.0, 65.0]);
+    }
+
+    #[test]
+    fn apply_replaces_and_adds_options() {
+        let args = strings(&["-c:v", "libx264", "-crf", "26", "-an"]);
+        assert_eq!(
+            apply(&args, &strings(&["-crf", "30", "-tune", "film"])),
+            strings(&[
+                "-c:v", "libx264", "-crf", "30", "-an", "-tune", "film"
+            ])
+        );
+    }
+
+    #[test]
+    fn apply_handles_flags_and_negative_values() {
+        let args = strings(&["-crf", "26", "-an"]);
+        assert_eq!(
+            apply(&args, &strings(&["-an", "-bf", "-1", "-sn"])),
+            strings(&["-crf", "26", "-an", "-bf", "-1", "-sn"])
+        );
+    }
+}